@@ -8,7 +8,7 @@ use phf_codegen::Map;
 
 fn extract_symbols(content: &str, map: &mut HashMap<String, String>) {
     for part in content.split("#define") {
-        let mut sw = part.trim_start().split_whitespace();
+        let mut sw = part.split_whitespace();
         match (sw.next(), sw.next()) {
             (Some(key), Some(value)) if value.starts_with("0x") => {
                 let int = u32::from_str_radix(value.trim_start_matches("0x"), 16).unwrap();
@@ -0,0 +1,166 @@
+use crossbeam_channel::Sender;
+
+use crate::config::RatchetMode;
+use crate::hid::HidHandler;
+use crate::StateChanges;
+
+/// Source of crown events, events are delivered as `StateChanges` and the
+/// source receives requests changing crown behavior.
+pub(crate) trait InputSource {
+    fn set_ratchet_mode(&self, mode: RatchetMode, smart_shift_threshold: u16);
+
+    /// Briefly flips ratchet mode to give tactile feedback.
+    fn end_stop(&self);
+
+    /// Puts device back into its default state before exit, waits until it's done.
+    fn restore(&self);
+}
+
+/// Compositor specific tracker of focused window.
+pub(crate) trait FocusSource {
+    fn name(&self) -> &'static str;
+
+    /// Connects to the compositor and starts sending `FocusChanged` events to `sender`,
+    /// fails when the compositor can't be used so that the next one can be tried.
+    fn spawn(&mut self, sender: Sender<StateChanges>) -> std::io::Result<()>;
+}
+
+/// Destination of synthetic key presses and scroll events.
+pub(crate) trait ActionSink {
+    fn name(&self) -> &'static str;
+
+    fn send_key(&self, keysym: u32, modifiers: u8);
+
+    fn scroll(&self, amount: i32);
+}
+
+/// Sink used when neither X11 nor Wayland display is available.
+struct HeadlessSink;
+
+impl ActionSink for HeadlessSink {
+    fn name(&self) -> &'static str {
+        "headless"
+    }
+
+    fn send_key(&self, keysym: u32, modifiers: u8) {
+        debug!("Headless: dropping key {:x?} {:x?}", keysym, modifiers);
+    }
+
+    fn scroll(&self, amount: i32) {
+        debug!("Headless: dropping scroll {}", amount);
+    }
+}
+
+pub(crate) struct Backends {
+    pub(crate) input: Box<dyn InputSource>,
+    /// Separate focus tracker, `None` when focus is tracked by `sink` or not at all.
+    pub(crate) focus: Option<Box<dyn FocusSource>>,
+    pub(crate) sink: Box<dyn ActionSink>,
+}
+
+/// Returns focus trackers of compositors running in current session.
+fn focus_candidates() -> Vec<Box<dyn FocusSource>> {
+    let candidates: Vec<Option<Box<dyn FocusSource>>> = vec![
+        #[cfg(feature = "sway")]
+        crate::sway::SwayHandler::detect().map(|h| Box::new(h) as Box<dyn FocusSource>),
+        #[cfg(feature = "hyprland")]
+        crate::hyprland::HyprlandHandler::detect().map(|h| Box::new(h) as Box<dyn FocusSource>),
+        #[cfg(feature = "kde")]
+        crate::kwin::KWinHandler::detect().map(|h| Box::new(h) as Box<dyn FocusSource>),
+    ];
+    candidates.into_iter().flatten().collect()
+}
+
+/// Returns the first of `candidates` which starts tracking focus.
+fn detect_focus(candidates: Vec<Box<dyn FocusSource>>, sender: &Sender<StateChanges>) -> Option<Box<dyn FocusSource>> {
+    candidates.into_iter().find_map(|mut source| match source.spawn(sender.clone()) {
+        Ok(()) => Some(source),
+        Err(err) => {
+            warn!("Can't use {} for focus tracking: {}", source.name(), err);
+            None
+        }
+    })
+}
+
+#[allow(unused_variables)]
+fn detect_sink(sender: &Sender<StateChanges>, track_focus: bool) -> Box<dyn ActionSink> {
+    #[cfg(feature = "wayland")]
+    {
+        if std::env::var_os("WAYLAND_DISPLAY").is_some() {
+            match crate::wayland::WaylandHandler::new(sender.clone(), track_focus) {
+                Ok(handler) => return Box::new(handler),
+                Err(err) => warn!("Can't use Wayland backend: {}", err),
+            }
+        }
+    }
+    #[cfg(feature = "x11")]
+    {
+        if std::env::var_os("DISPLAY").is_some() {
+            match crate::x11::X11Handler::new(sender.clone(), track_focus) {
+                Ok(handler) => return Box::new(handler),
+                Err(err) => warn!("Can't use X11 backend: {}", err),
+            }
+        }
+    }
+    warn!("No display available, running headless");
+    Box::new(HeadlessSink)
+}
+
+/// Picks backends matching current session: separate focus tracker for sway,
+/// Hyprland and Plasma, then Wayland or X11 for sending keys, falling back to
+/// headless mode when no display is available.
+pub(crate) fn detect(sender: &Sender<StateChanges>) -> std::io::Result<Backends> {
+    let focus = detect_focus(focus_candidates(), sender);
+    let sink = detect_sink(sender, focus.is_none());
+    let input = HidHandler::new(sender.clone())?;
+
+    Ok(Backends {
+        input: Box::new(input),
+        focus,
+        sink,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use super::*;
+
+    struct FakeFocus {
+        name: &'static str,
+        works: bool,
+        spawned: Rc<Cell<usize>>,
+    }
+
+    impl FocusSource for FakeFocus {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        fn spawn(&mut self, _sender: Sender<StateChanges>) -> std::io::Result<()> {
+            self.spawned.set(self.spawned.get() + 1);
+            if self.works {
+                Ok(())
+            } else {
+                Err(std::io::Error::new(std::io::ErrorKind::ConnectionRefused, "not running"))
+            }
+        }
+    }
+
+    #[test]
+    fn first_working_focus_source_is_used() {
+        let (sender, _receiver) = crossbeam_channel::unbounded();
+        let spawned = Rc::new(Cell::new(0));
+        let fake = |name, works| Box::new(FakeFocus { name, works, spawned: spawned.clone() }) as Box<dyn FocusSource>;
+
+        let focus = detect_focus(vec![fake("sway", false), fake("hyprland", true), fake("kwin", true)], &sender);
+        assert_eq!(focus.map(|f| f.name()), Some("hyprland"));
+        // Sources after the working one aren't started
+        assert_eq!(spawned.get(), 2);
+
+        assert!(detect_focus(vec![fake("sway", false)], &sender).is_none());
+        assert!(detect_focus(Vec::new(), &sender).is_none());
+    }
+}
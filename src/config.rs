@@ -1,5 +1,5 @@
 use std::collections::HashMap;
-use std::fs::{File, metadata};
+use std::fs::{create_dir_all, File, metadata};
 use std::ops::Sub;
 use std::path::PathBuf;
 use std::rc::Rc;
@@ -8,9 +8,14 @@ use std::time::{Duration, Instant, SystemTime};
 use directories::ProjectDirs;
 use serde::{Deserialize, Deserializer, Serialize};
 
+use crate::process::script_name;
+
 #[derive(Debug, Serialize, Deserialize)]
-#[serde(transparent)]
 pub(crate) struct Config {
+    /// Commands of plugins started with the daemon.
+    #[serde(default)]
+    pub(crate) plugins: Vec<String>,
+    #[serde(flatten)]
     pub(crate) app: HashMap<String, Rc<AppMapping>>
 }
 
@@ -19,20 +24,68 @@ pub(crate) struct AppMapping {
     #[serde(default)]
     pub(crate) mode: RatchetMode,
     pub(crate) mapping: HashMap<Modifier, Rc<ButtonMapping>>,
+    #[serde(default)]
+    pub(crate) values: HashMap<String, Rc<ValueDefinition>>,
+    /// Sum of rotation amounts in 100ms needed to free crown in `SmartShift` mode.
+    #[serde(default = "default_smart_shift_threshold")]
+    pub(crate) smart_shift_threshold: u16,
+    /// Keep values and toggle positions of this profile across restarts.
+    #[serde(default)]
+    pub(crate) persist_state: bool,
 }
 
-#[derive(Debug, Serialize, Deserialize, Copy, Clone, Eq, PartialEq)]
-pub(crate) enum RatchetMode {
-    Free,
-    Ratcheted,
+fn default_smart_shift_threshold() -> u16 {
+    40
+}
+
+/// Numeric value changed with `Adjust` operation, `on_change` operations are
+/// executed with `{value}` replaced by the new value.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct ValueDefinition {
+    pub(crate) value: f64,
+    pub(crate) min: f64,
+    pub(crate) max: f64,
+    #[serde(default = "default_value_step")]
+    pub(crate) step: f64,
+    #[serde(default)]
+    pub(crate) on_change: Vec<Operation>,
 }
 
-impl Default for RatchetMode {
-    fn default() -> Self {
-        Self::Ratcheted
+fn default_value_step() -> f64 {
+    1.0
+}
+
+impl ValueDefinition {
+    /// Rounds `value` to the number of decimal places used in the definition,
+    /// so repeated steps like `0.1` don't accumulate float errors.
+    fn round(&self, value: f64) -> f64 {
+        let decimals = [self.value, self.min, self.max, self.step].iter().
+            map(|v| v.to_string().split('.').nth(1).map_or(0, |d| d.len())).
+            max().
+            unwrap_or(0).
+            min(10);
+        let scale = 10f64.powi(decimals as i32);
+        (value * scale).round() / scale
     }
 }
 
+pub(crate) struct ValueChange {
+    pub(crate) profile: String,
+    pub(crate) value: f64,
+    pub(crate) changed: bool,
+    pub(crate) at_bound: bool,
+    pub(crate) definition: Rc<ValueDefinition>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, Eq, PartialEq, Default)]
+pub(crate) enum RatchetMode {
+    Free,
+    #[default]
+    Ratcheted,
+    /// Ratcheted, switching to free while crown rotates fast.
+    SmartShift,
+}
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub(crate) enum Modifier {
     None,
@@ -41,7 +94,8 @@ pub(crate) enum Modifier {
     Ctrl,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub(crate) enum Action {
     Touch,
     Release,
@@ -52,6 +106,17 @@ pub(crate) enum Action {
     Click,
 }
 
+impl From<Modifier> for u8 {
+    fn from(v: Modifier) -> Self {
+        match v {
+            Modifier::None => 0,
+            Modifier::Shift => 0x02,
+            Modifier::Alt => 0x04,
+            Modifier::Ctrl => 0x01,
+        }
+    }
+}
+
 impl From<u8> for Modifier {
     fn from(v: u8) -> Self {
         if v & 0x44 != 0 {
@@ -66,38 +131,115 @@ impl From<u8> for Modifier {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub(crate) enum Operation {
     #[serde(deserialize_with = "deserialize_string_lowercase")]
     KeyPress(u32, u8),
     Execute(String),
+    Batch {
+        command: String,
+        #[serde(default = "default_batch_idle")]
+        idle: u64,
+    },
+    Mpris(MprisOperation),
+    Adjust(String),
+    Toggle {
+        #[serde(default)]
+        on: Vec<Operation>,
+        #[serde(default)]
+        off: Vec<Operation>,
+    },
+    Cycle(Vec<Vec<Operation>>),
+    SetRatchet(RatchetMode),
+    ToggleRatchet,
+    /// Scrolls by given number of steps per rotation step, positive values scroll down.
+    Scroll(i32),
+    /// Calls function from `config.rhai` with the crown event.
+    Script(String),
 }
 
-fn deserialize_string_lowercase<'de, D>(deserializer: D) -> Result<(u32, u8), D::Error>
-    where
-        D: Deserializer<'de>,
-{
-    let s = String::deserialize(deserializer)?.to_lowercase();
+fn default_batch_idle() -> u64 {
+    200
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub(crate) enum MprisCommand {
+    PlayPause,
+    Play,
+    Pause,
+    Stop,
+    Next,
+    Previous,
+    /// Seeks by given number of seconds per rotation step.
+    Seek(f64),
+    /// Changes volume by given fraction per rotation step.
+    Volume(f64),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub(crate) struct MprisOperation {
+    pub(crate) command: MprisCommand,
+    /// Name of the player (`spotify` matches `org.mpris.MediaPlayer2.spotify`),
+    /// most recently active player is used when not set.
+    #[serde(default)]
+    pub(crate) player: Option<String>,
+}
+
+/// Parses key like `Ctrl+Tab` to keysym and modifiers mask.
+pub(crate) fn parse_key(key: &str) -> Result<(u32, u8), String> {
+    let s = key.to_lowercase();
     let mut iter = s.rsplit('+');
-    use serde::de::Error;
     if let Some(key) = iter.next() {
         let keycode = crate::keysyms::KEYSYMS.get(key).
-            map_or_else(|| Err(Error::custom(format!("Unknown keysym: {}", key))), |v| Ok(*v))?;
+            map_or_else(|| Err(format!("Unknown keysym: {}", key)), |v| Ok(*v))?;
         let mut modifiers = 0;
         for modifier in iter {
             match modifier {
-                "shift" => modifiers = modifiers | 1,
-                "alt" => modifiers = modifiers | 8,
-                "ctrl" => modifiers = modifiers | 4,
+                "shift" => modifiers |= 1,
+                "alt" => modifiers |= 8,
+                "ctrl" => modifiers |= 4,
                 _ => {}
             }
         }
         Ok((keycode, modifiers))
     } else {
-        Err(Error::custom("Can't parse keysym"))
+        Err("Can't parse keysym".to_owned())
     }
 }
 
+/// Formats keysym and modifiers mask parsed by `parse_key` back to readable form like `ctrl+tab`.
+pub(crate) fn key_name(keysym: u32, modifiers: u8) -> String {
+    // Several names can map to the same keysym, the shortest one is used
+    let name = crate::keysyms::KEYSYMS.entries().
+        filter(|(_, v)| **v == keysym).
+        map(|(k, _)| *k).
+        min_by_key(|k| (k.len(), *k)).
+        map_or_else(|| format!("{:#x}", keysym), str::to_owned);
+    let mut parts: Vec<&str> = [(4, "ctrl"), (8, "alt"), (1, "shift")].iter().
+        filter(|(mask, _)| modifiers & mask != 0).
+        map(|(_, name)| *name).
+        collect();
+    parts.push(&name);
+    parts.join("+")
+}
+
+/// Formats `operations` for people, with key names instead of keysyms.
+pub(crate) fn describe_operations(operations: &[Operation]) -> String {
+    let operations: Vec<String> = operations.iter().map(|op| match op {
+        Operation::KeyPress(keysym, modifiers) => format!("KeyPress({})", key_name(*keysym, *modifiers)),
+        _ => format!("{:?}", op),
+    }).collect();
+    format!("[{}]", operations.join(", "))
+}
+
+fn deserialize_string_lowercase<'de, D>(deserializer: D) -> Result<(u32, u8), D::Error>
+    where
+        D: Deserializer<'de>,
+{
+    use serde::de::Error;
+    parse_key(&String::deserialize(deserializer)?).map_err(Error::custom)
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub(crate) struct ButtonMapping {
     #[serde(default)]
@@ -116,6 +258,18 @@ pub(crate) struct ButtonMapping {
     pub(crate) left_pressed: Vec<Operation>,
     #[serde(default)]
     pub(crate) right_pressed: Vec<Operation>,
+    #[serde(default)]
+    pub(crate) cooldown: HashMap<Action, u64>,
+}
+
+/// Runtime state of a profile: current numeric values and positions of
+/// `Toggle`/`Cycle` operations keyed by their place in the mapping.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ProfileState {
+    #[serde(default)]
+    values: HashMap<String, f64>,
+    #[serde(default)]
+    positions: HashMap<String, usize>,
 }
 
 //#[derive(Debug)]
@@ -125,8 +279,29 @@ pub struct ConfigFile {
     mtime: SystemTime,
     last_mtime_check: Instant,
     active_app: Option<String>,
+    active_sandbox_id: Option<String>,
+    /// Names of focused process and of foreground process of focused terminal.
+    active_names: Vec<String>,
+    active_foreground_names: Vec<String>,
+    active_class: Option<String>,
     global_conf: Option<Rc<AppMapping>>,
     active_conf: Option<Rc<AppMapping>>,
+    active_profile: Option<String>,
+    forced_profile: Option<String>,
+    state: HashMap<String, ProfileState>,
+    state_path: Option<PathBuf>,
+}
+
+/// Returns names matching process: script run by interpreter (full path and name),
+/// full program path, program name and `argv[0]`.
+fn process_names(program: &str, cmdline: &[String]) -> Vec<String> {
+    let script = script_name(cmdline);
+    let paths = script.as_deref().into_iter().
+        chain(Some(program)).
+        chain(cmdline.first().map(String::as_str));
+    paths.filter(|path| !path.is_empty()).
+        flat_map(|path| vec![path.to_owned(), path.rsplit('/').next().unwrap_or(path).to_owned()]).
+        collect()
 }
 
 impl ConfigFile {
@@ -134,14 +309,25 @@ impl ConfigFile {
         let mut conf =
             if let Some(dirs) = ProjectDirs::from("org", "prefiks", "crown-controller") {
                 let path = dirs.config_dir().join("config.yaml");
+                let state_path = dirs.data_dir().join("state.yaml");
                 ConfigFile {
                     path: Some(path),
                     config: None,
                     mtime: SystemTime::now(),
                     last_mtime_check: Instant::now().sub(Duration::from_secs(1000)),
                     active_app: None,
+                    active_sandbox_id: None,
+                    active_names: Vec::new(),
+                    active_foreground_names: Vec::new(),
+                    active_class: None,
                     global_conf: None,
                     active_conf: None,
+                    active_profile: None,
+                    forced_profile: None,
+                    state: File::open(&state_path).ok().
+                        and_then(|f| serde_yaml::from_reader(f).ok()).
+                        unwrap_or_default(),
+                    state_path: Some(state_path),
                 }
             } else {
                 ConfigFile {
@@ -150,21 +336,78 @@ impl ConfigFile {
                     mtime: SystemTime::now(),
                     last_mtime_check: Instant::now().sub(Duration::from_secs(1000)),
                     active_app: None,
+                    active_sandbox_id: None,
+                    active_names: Vec::new(),
+                    active_foreground_names: Vec::new(),
+                    active_class: None,
                     global_conf: None,
                     active_conf: None,
+                    active_profile: None,
+                    forced_profile: None,
+                    state: HashMap::new(),
+                    state_path: None,
                 }
             };
         conf.maybe_load_config();
         conf
     }
 
-    pub(crate) fn select_app(&mut self, app: &str) {
+    /// Selects profile of focused application, profiles are matched by Flatpak id or Snap name,
+    /// names of foreground process when terminal is focused, names of the focused process
+    /// and then by window class (or Wayland app_id).
+    pub(crate) fn select_app(&mut self, app: &str, sandbox_id: Option<&str>, cmdline: &[String], class: &str) {
         self.active_app = Some(app.to_owned());
+        self.active_sandbox_id = sandbox_id.map(|id| id.to_owned());
+        self.active_names = process_names(app, cmdline);
+        self.active_foreground_names = Vec::new();
+        self.active_class = Some(class.to_owned()).filter(|v| !v.is_empty());
+        self.maybe_load_config();
+        self.update_app_config();
+    }
+
+    /// Sets foreground process of the focused terminal, it takes precedence over
+    /// the terminal itself when selecting profile.
+    pub(crate) fn select_foreground(&mut self, program: &str, cmdline: &[String]) {
+        self.active_foreground_names = process_names(program, cmdline);
+        self.maybe_load_config();
+        self.update_app_config();
+    }
+
+    /// Uses `profile` regardless of focused application, `None` restores automatic
+    /// selection. Returns `false` when profile isn't defined.
+    pub(crate) fn force_profile(&mut self, profile: Option<String>) -> bool {
         self.maybe_load_config();
+        if profile.as_ref().is_some_and(|p| !self.config.as_ref().is_some_and(|c| c.app.contains_key(p))) {
+            return false;
+        }
+        self.forced_profile = profile;
         self.update_app_config();
+        true
+    }
+
+    /// Returns plugin commands from config.
+    pub(crate) fn plugins(&mut self) -> Vec<String> {
+        self.maybe_load_config();
+        self.config.as_ref().map_or_else(Vec::new, |c| c.plugins.clone())
+    }
+
+    pub(crate) fn active_app(&self) -> Option<&str> {
+        self.active_app.as_deref()
+    }
+
+    pub(crate) fn active_class(&self) -> Option<&str> {
+        self.active_class.as_deref()
+    }
+
+    pub(crate) fn active_profile(&self) -> Option<&str> {
+        self.active_profile.as_deref()
+    }
+
+    pub(crate) fn forced_profile(&self) -> Option<&str> {
+        self.forced_profile.as_deref()
     }
 
-    fn get_actions_from_mapping(mapping: &Rc<ButtonMapping>, action: Action) -> Option<&[Operation]> {
+    pub(crate) fn get_actions_from_mapping(mapping: &ButtonMapping, action: Action) -> Option<&[Operation]> {
         let actions = match action {
             Action::Touch => mapping.touch.as_slice(),
             Action::Release => mapping.release.as_slice(),
@@ -181,14 +424,85 @@ impl ConfigFile {
         }
     }
 
-    pub(crate) fn get_actions_for_modifiers(&mut self, modifiers: Modifier, action: Action) -> Option<&[Operation]> {
+    /// Returns mapping with operations for `action` together with name of
+    /// the profile it comes from.
+    pub(crate) fn get_mapping_for_modifiers(&mut self, modifiers: Modifier, action: Action) -> Option<(String, Rc<ButtonMapping>)> {
         self.maybe_load_config();
         let (active_conf, global_conf) = (self.active_conf.as_ref(), self.global_conf.as_ref());
 
         active_conf.and_then(|v| v.mapping.get(&modifiers).
-            and_then(|v2| Self::get_actions_from_mapping(v2, action))).
-            or_else(|| global_conf.and_then(|ref v| v.mapping.get(&modifiers).
-                and_then(|v2| Self::get_actions_from_mapping(v2, action))))
+            filter(|v2| Self::get_actions_from_mapping(v2, action).is_some())).
+            and_then(|v| self.active_profile.clone().map(|p| (p, v.clone()))).
+            or_else(|| global_conf.and_then(|v| v.mapping.get(&modifiers).
+                filter(|v2| Self::get_actions_from_mapping(v2, action).is_some())).
+                map(|v| ("global".to_owned(), v.clone())))
+    }
+
+    /// Changes value `name` from active or global profile by `delta` steps,
+    /// keeping it in min/max range.
+    pub(crate) fn adjust_value(&mut self, name: &str, delta: i16) -> Option<ValueChange> {
+        self.maybe_load_config();
+        let active = self.active_profile.as_ref().
+            and_then(|p| self.active_conf.as_ref().
+                and_then(|v| v.values.get(name)).
+                map(|d| (p.clone(), d.clone())));
+        let (profile, definition) = active.
+            or_else(|| self.global_conf.as_ref().
+                and_then(|v| v.values.get(name)).
+                map(|d| ("global".to_owned(), d.clone())))?;
+
+        let current = self.state.entry(profile.clone()).or_default().
+            values.entry(name.to_owned()).or_insert(definition.value);
+        let old = current.max(definition.min).min(definition.max);
+        let value = definition.round(old + definition.step * delta as f64).max(definition.min).min(definition.max);
+        *current = value;
+        if value != old {
+            self.save_state(&profile);
+        }
+
+        Some(ValueChange {
+            profile,
+            value,
+            changed: value != old,
+            at_bound: (delta > 0 && value >= definition.max) || (delta < 0 && value <= definition.min),
+            definition,
+        })
+    }
+
+    /// Returns position of `Toggle` or `Cycle` operation identified by `key`
+    /// in `profile` and advances it to the next one. Operations from global profile
+    /// have separate position for each active application profile.
+    pub(crate) fn next_position(&mut self, profile: &str, key: &str, len: usize) -> usize {
+        let key = match self.active_profile {
+            Some(ref active) if profile == "global" => format!("{}:{}", active, key),
+            _ => key.to_owned(),
+        };
+        let position = self.state.entry(profile.to_owned()).or_default().
+            positions.entry(key).or_insert(0);
+        let current = *position % len.max(1);
+        *position = (current + 1) % len.max(1);
+        self.save_state(profile);
+        current
+    }
+
+    fn save_state(&self, profile: &str) {
+        let persisted = |name: &str| self.config.as_ref().
+            and_then(|c| c.app.get(name)).
+            is_some_and(|v| v.persist_state);
+        if let (true, Some(path)) = (persisted(profile), self.state_path.as_ref()) {
+            let state: HashMap<_, _> = self.state.iter().filter(|(name, _)| persisted(name)).collect();
+            if let Some(dir) = path.parent() {
+                let _ = create_dir_all(dir);
+            }
+            match File::create(path) {
+                Ok(file) => {
+                    if let Err(err) = serde_yaml::to_writer(file, &state) {
+                        error!("Can't save state: {}", err);
+                    }
+                }
+                Err(err) => error!("Can't save state to {:?}: {}", path, err),
+            }
+        }
     }
 
     pub(crate) fn ratchet_mode_for_modifier(&mut self, modifiers: Modifier) -> RatchetMode {
@@ -197,11 +511,107 @@ impl ConfigFile {
 
         active_conf.and_then(|v| v.mapping.get(&modifiers).
             and_then(|v2| v2.mode.or_else(|| Some(v.mode)))).
-            or_else(|| global_conf.and_then(|ref v| v.mapping.get(&modifiers).
+            or_else(|| global_conf.and_then(|v| v.mapping.get(&modifiers).
                 and_then(|v2| v2.mode.or_else(|| Some(v.mode))))).
             map_or(RatchetMode::Ratcheted, |v| v)
     }
 
+    /// Describes how profile, operations and ratchet mode for `modifiers` and `action` are resolved
+    /// for selected application, including candidates that didn't match.
+    pub(crate) fn explain(&mut self, modifiers: Modifier, action: Action) -> Vec<String> {
+        self.maybe_load_config();
+        let mut lines = Vec::new();
+        let path = self.path.as_ref().map_or_else(|| "unknown path".to_owned(), |p| p.display().to_string());
+        let config = match self.config {
+            Some(ref config) => config,
+            None => {
+                lines.push(format!("No valid config loaded from {}", path));
+                return lines;
+            }
+        };
+        lines.push(format!("Config {}", path));
+
+        // Same order as `update_app_config` uses
+        lines.push("Profile candidates, in order of precedence:".to_owned());
+        let candidates = self.forced_profile.iter().map(|name| ("forced profile", name)).
+            chain(self.active_sandbox_id.iter().map(|name| ("sandbox id", name))).
+            chain(self.active_foreground_names.iter().map(|name| ("terminal foreground process", name))).
+            chain(self.active_names.iter().map(|name| ("process", name))).
+            chain(self.active_class.iter().map(|name| ("window class", name)));
+        let mut selected = false;
+        let mut checked = Vec::new();
+        for (kind, name) in candidates {
+            if checked.contains(&name) {
+                continue;
+            }
+            checked.push(name);
+            let result = if !config.app.contains_key(name) {
+                "no such profile"
+            } else if selected {
+                "skipped, profile already selected"
+            } else {
+                selected = true;
+                "selected"
+            };
+            lines.push(format!("  {} {}: {}", kind, name, result));
+        }
+        match self.active_profile.as_deref() {
+            Some("global") => lines.push("Active profile global".to_owned()),
+            Some(profile) => lines.push(format!("Active profile {}, falling back to global", profile)),
+            None => lines.push("No profile matched, using global".to_owned()),
+        }
+
+        // Active profile named `global` is the global one, it's looked up only once
+        let layers = self.active_profile.as_deref().zip(self.active_conf.as_ref()).into_iter().
+            chain(Some("global").zip(self.global_conf.as_ref()).filter(|_| self.active_profile.as_deref() != Some("global")));
+        lines.push(format!("Operations for {:?} layer, action {:?}:", modifiers, action));
+        let mut found = false;
+        for (profile, conf) in layers.clone() {
+            let result = match conf.mapping.get(&modifiers) {
+                None => format!("no {:?} mapping", modifiers),
+                Some(mapping) => match Self::get_actions_from_mapping(mapping, action) {
+                    None => format!("{:?} mapping has no {:?} operations", modifiers, action),
+                    Some(_) if found => "skipped, operations already found".to_owned(),
+                    Some(operations) => {
+                        found = true;
+                        format!("used {}", describe_operations(operations))
+                    }
+                },
+            };
+            lines.push(format!("  profile {}: {}", profile, result));
+        }
+        if self.global_conf.is_none() {
+            lines.push("  profile global: not defined".to_owned());
+        }
+
+        lines.push(format!("Ratchet mode for {:?} layer:", modifiers));
+        let mut found = false;
+        for (profile, conf) in layers {
+            let result = match conf.mapping.get(&modifiers) {
+                None => format!("no {:?} mapping", modifiers),
+                Some(_) if found => "skipped, mode already found".to_owned(),
+                Some(mapping) => {
+                    found = true;
+                    match mapping.mode {
+                        Some(mode) => format!("used {:?} from {:?} mapping", mode, modifiers),
+                        None => format!("used profile mode {:?}, {:?} mapping doesn't set mode", conf.mode, modifiers),
+                    }
+                }
+            };
+            lines.push(format!("  profile {}: {}", profile, result));
+        }
+        if !found {
+            lines.push(format!("  default {:?}", RatchetMode::default()));
+        }
+        lines
+    }
+
+    pub(crate) fn smart_shift_threshold(&mut self) -> u16 {
+        self.maybe_load_config();
+        self.active_conf.as_ref().or(self.global_conf.as_ref()).
+            map_or_else(default_smart_shift_threshold, |v| v.smart_shift_threshold)
+    }
+
     fn maybe_load_config(&mut self) {
         if let Some(ref path) = self.path {
             if self.last_mtime_check.elapsed() > Duration::from_secs(1) {
@@ -213,19 +623,20 @@ impl ConfigFile {
                     if let Ok(config_file) = File::open(path) {
                         match serde_yaml::from_reader::<_, Config>(&config_file) {
                             Ok(config) => {
-                                self.global_conf = config.app.get("global").map_or(None, |v| Some(v.clone()));
+                                self.global_conf = config.app.get("global").cloned();
                                 self.config = Some(config);
                                 self.update_app_config();
                             }
                             Err(err) => {
-                                println!("Can't load config: {:?}", err);
+                                error!("Can't load config {:?}: {}", path, err);
                                 self.config = None;
                                 self.global_conf = None;
                                 self.active_conf = None;
+                                self.active_profile = None;
                             }
                         }
                     } else {
-                        println!("Can't open config file {:?}", path);
+                        error!("Can't open config file {:?}", path);
                     }
                     self.mtime = mtime;
                 }
@@ -235,14 +646,286 @@ impl ConfigFile {
     }
     fn update_app_config(&mut self) {
         if let Some(ref conf) = self.config {
-            if let Some(app) = &self.active_app {
-                self.active_conf = conf.app.get(app).map_or(None, |v| Some(v.clone()));
-                if self.active_conf.is_none() {
-                    if let Some(app) = app.rsplit("/").next() {
-                        self.active_conf = conf.app.get(app).map_or(None, |v| Some(v.clone()));
-                    }
-                }
-            }
+            self.active_profile = if let Some(profile) = self.forced_profile.clone().filter(|p| conf.app.contains_key(p)) {
+                Some(profile)
+            } else {
+                self.active_sandbox_id.iter().
+                    chain(self.active_foreground_names.iter()).
+                    chain(self.active_names.iter()).
+                    chain(self.active_class.iter()).
+                    find(|name| conf.app.contains_key(*name)).
+                    cloned()
+            };
+            self.active_conf = self.active_profile.as_ref().and_then(|app| conf.app.get(app).cloned());
+        }
+    }
+}
+
+#[cfg(test)]
+impl ConfigFile {
+    /// Returns config parsed from `yaml`, which isn't reloaded and doesn't store state.
+    pub(crate) fn from_yaml(yaml: &str) -> ConfigFile {
+        let config: Config = serde_yaml::from_str(yaml).unwrap();
+        ConfigFile {
+            global_conf: config.app.get("global").cloned(),
+            config: Some(config),
+            path: None,
+            mtime: SystemTime::now(),
+            last_mtime_check: Instant::now(),
+            active_app: None,
+            active_sandbox_id: None,
+            active_names: Vec::new(),
+            active_foreground_names: Vec::new(),
+            active_class: None,
+            forced_profile: None,
+            active_conf: None,
+            active_profile: None,
+            state: HashMap::new(),
+            state_path: None,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VALUES: &str = "global:
+  mapping: {}
+  values:
+    opacity: { value: 0.2, min: 0.0, max: 0.5, step: 0.1 }
+";
+
+    #[test]
+    fn key_names_round_trip() {
+        let (keysym, modifiers) = parse_key("Shift+Ctrl+Tab").unwrap();
+        assert_eq!(key_name(keysym, modifiers), "ctrl+shift+tab");
+        assert_eq!(parse_key(&key_name(keysym, modifiers)), Ok((keysym, modifiers)));
+        assert_eq!(key_name(0x10fffff, 0), "0x10fffff");
+    }
+
+    const PROFILES: &str = r#"
+global:
+  mapping:
+    None:
+      right:
+        - KeyPress: "Ctrl+Tab"
+    Shift:
+      mode: Free
+      right:
+        - Scroll: 1
+org.gimp.GIMP: { mapping: {} }
+vim: { mapping: {} }
+kitty:
+  mode: Free
+  mapping:
+    None:
+      right:
+        - KeyPress: "a"
+    Shift:
+      click:
+        - KeyPress: "b"
+kitty-class: { mapping: {} }
+forced: { mapping: {} }
+"#;
+
+    /// Returns lines describing profile candidates.
+    fn candidates(config: &mut ConfigFile) -> Vec<String> {
+        config.explain(Modifier::None, Action::Right).into_iter().
+            skip_while(|l| !l.starts_with("Profile candidates")).
+            skip(1).
+            take_while(|l| l.starts_with("  ")).
+            collect()
+    }
+
+    #[test]
+    fn explained_candidates_follow_precedence() {
+        let mut config = ConfigFile::from_yaml(PROFILES);
+        config.select_app("/usr/bin/kitty", Some("org.gimp.GIMP"), &["kitty".to_owned()], "kitty-class");
+        config.select_foreground("/usr/bin/vim", &["vim".to_owned()]);
+        config.force_profile(Some("forced".to_owned()));
+        assert_eq!(candidates(&mut config), vec![
+            "  forced profile forced: selected",
+            "  sandbox id org.gimp.GIMP: skipped, profile already selected",
+            "  terminal foreground process /usr/bin/vim: no such profile",
+            "  terminal foreground process vim: skipped, profile already selected",
+            "  process /usr/bin/kitty: no such profile",
+            "  process kitty: skipped, profile already selected",
+            "  window class kitty-class: skipped, profile already selected",
+        ]);
+
+        // Each candidate is selected once those before it are gone, like the daemon does
+        config.force_profile(None);
+        assert_eq!(config.active_profile(), Some("org.gimp.GIMP"));
+        assert_eq!(candidates(&mut config)[0], "  sandbox id org.gimp.GIMP: selected");
+        config.select_app("/usr/bin/kitty", None, &["kitty".to_owned()], "kitty-class");
+        config.select_foreground("/usr/bin/vim", &["vim".to_owned()]);
+        assert_eq!(config.active_profile(), Some("vim"));
+        assert_eq!(candidates(&mut config)[1], "  terminal foreground process vim: selected");
+        config.select_app("/usr/bin/kitty", None, &["kitty".to_owned()], "kitty-class");
+        assert_eq!(config.active_profile(), Some("kitty"));
+        assert_eq!(candidates(&mut config)[1], "  process kitty: selected");
+        config.select_app("/usr/bin/xterm", None, &["xterm".to_owned()], "kitty-class");
+        assert_eq!(config.active_profile(), Some("kitty-class"));
+        assert_eq!(candidates(&mut config)[2], "  window class kitty-class: selected");
+        config.select_app("/usr/bin/xterm", None, &["xterm".to_owned()], "");
+        assert_eq!(config.active_profile(), None);
+        assert!(config.explain(Modifier::None, Action::Right).contains(&"No profile matched, using global".to_owned()));
+    }
+
+    #[test]
+    fn explained_operations_fall_back_to_global() {
+        let mut config = ConfigFile::from_yaml(PROFILES);
+        config.select_app("/usr/bin/kitty", None, &["kitty".to_owned()], "");
+        let lines = config.explain(Modifier::Shift, Action::Right);
+        let operations = lines.iter().position(|l| l == "Operations for Shift layer, action Right:").unwrap();
+        assert_eq!(lines[operations + 1..], [
+            "  profile kitty: Shift mapping has no Right operations",
+            "  profile global: used [Scroll(1)]",
+            "Ratchet mode for Shift layer:",
+            "  profile kitty: used profile mode Free, Shift mapping doesn't set mode",
+            "  profile global: skipped, mode already found",
+        ]);
+        assert_eq!(config.ratchet_mode_for_modifier(Modifier::Shift), RatchetMode::Free);
+
+        let lines = config.explain(Modifier::None, Action::Right);
+        assert!(lines.contains(&"  profile kitty: used [KeyPress(a)]".to_owned()));
+        assert!(lines.contains(&"  profile global: skipped, operations already found".to_owned()));
+
+        // Forced global profile is looked up once
+        config.force_profile(Some("global".to_owned()));
+        let lines = config.explain(Modifier::Alt, Action::Right);
+        let operations = lines.iter().position(|l| l == "Operations for Alt layer, action Right:").unwrap();
+        assert_eq!(lines[operations - 1], "Active profile global");
+        assert_eq!(lines[operations + 1..], [
+            "  profile global: no Alt mapping",
+            "Ratchet mode for Alt layer:",
+            "  profile global: no Alt mapping",
+            "  default Ratcheted",
+        ]);
+        assert_eq!(describe_operations(&config.get_mapping_for_modifiers(Modifier::None, Action::Right).unwrap().1.right),
+                   "[KeyPress(ctrl+tab)]");
+    }
+
+    #[test]
+    fn adjusted_value_is_rounded_to_step() {
+        let mut config = ConfigFile::from_yaml(VALUES);
+        let change = config.adjust_value("opacity", 1).unwrap();
+        assert_eq!(change.value, 0.3);
+        assert_eq!(change.value.to_string(), "0.3");
+        assert_eq!(config.adjust_value("opacity", -3).unwrap().value, 0.0);
+        assert!(config.adjust_value("missing", 1).is_none());
+    }
+
+    #[test]
+    fn adjusted_value_stops_at_bounds() {
+        let mut config = ConfigFile::from_yaml(VALUES);
+        let change = config.adjust_value("opacity", 2).unwrap();
+        assert!(change.changed && !change.at_bound);
+        assert_eq!(change.value, 0.4);
+        let change = config.adjust_value("opacity", 5).unwrap();
+        assert!(change.changed && change.at_bound);
+        assert_eq!(change.value, 0.5);
+        let change = config.adjust_value("opacity", 1).unwrap();
+        assert!(!change.changed && change.at_bound);
+        // Moving away from the bound isn't end stop
+        let change = config.adjust_value("opacity", -1).unwrap();
+        assert!(change.changed && !change.at_bound);
+
+        let change = config.adjust_value("opacity", -10).unwrap();
+        assert!(change.changed && change.at_bound);
+        assert_eq!(change.value, 0.0);
+        let change = config.adjust_value("opacity", -1).unwrap();
+        assert!(!change.changed && change.at_bound);
+        let change = config.adjust_value("opacity", 1).unwrap();
+        assert!(change.changed && !change.at_bound);
+        assert_eq!(change.value, 0.1);
+    }
+
+    #[test]
+    fn toggle_and_cycle_positions_advance() {
+        let mut config = ConfigFile::from_yaml("global:\n  mapping: {}\nfirefox:\n  mapping: {}\n");
+        assert_eq!((0..5).map(|_| config.next_position("global", "None/Click/0", 2)).collect::<Vec<_>>(),
+                   vec![0, 1, 0, 1, 0]);
+        assert_eq!((0..4).map(|_| config.next_position("global", "None/Right/0", 3)).collect::<Vec<_>>(),
+                   vec![0, 1, 2, 0]);
+        // Shorter cycle after config change wraps around
+        assert_eq!(config.next_position("global", "None/Right/0", 1), 0);
+    }
+
+    #[test]
+    fn global_positions_are_kept_per_profile() {
+        let mut config = ConfigFile::from_yaml("global:\n  mapping: {}\nfirefox:\n  mapping: {}\n");
+        assert_eq!(config.next_position("global", "None/Click/0", 2), 0);
+        config.select_app("/usr/lib/firefox/firefox", None, &[], "");
+        assert_eq!(config.next_position("global", "None/Click/0", 2), 0);
+        assert_eq!(config.next_position("global", "None/Click/0", 2), 1);
+        config.select_app("/usr/bin/gimp", None, &[], "");
+        assert_eq!(config.next_position("global", "None/Click/0", 2), 1);
+        // Own operations of the profile aren't affected
+        assert_eq!(config.next_position("firefox", "None/Click/0", 2), 0);
+    }
+
+    #[test]
+    fn profile_is_matched_by_sandbox_id_path_name_and_class() {
+        let mut config = ConfigFile::from_yaml("global:\n  mapping: {}\n/opt/app/bin/app:\n  mapping: {}\n\
+                                      firefox:\n  mapping: {}\nthunderbird:\n  mapping: {}\n\
+                                      org.mozilla.firefox:\n  mapping: {}\n");
+        config.select_app("/app/lib/firefox/firefox", Some("org.mozilla.firefox"), &[], "firefox");
+        assert_eq!(config.active_profile.as_deref(), Some("org.mozilla.firefox"));
+        config.select_app("/app/lib/firefox/firefox", Some("org.example.Other"), &[], "");
+        assert_eq!(config.active_profile.as_deref(), Some("firefox"));
+        config.select_app("/opt/app/bin/app", None, &[], "firefox");
+        assert_eq!(config.active_profile.as_deref(), Some("/opt/app/bin/app"));
+        config.select_app("/usr/lib/firefox/firefox", None, &[], "thunderbird");
+        assert_eq!(config.active_profile.as_deref(), Some("firefox"));
+        config.select_app("", None, &[], "thunderbird");
+        assert_eq!(config.active_profile.as_deref(), Some("thunderbird"));
+        config.select_app("/usr/bin/gimp", None, &[], "Gimp");
+        assert_eq!(config.active_profile, None);
+    }
+
+    #[test]
+    fn profile_is_matched_by_script_and_argv0() {
+        let mut config = ConfigFile::from_yaml("global:\n  mapping: {}\nanki:\n  mapping: {}\n\
+                                      /opt/tool/run.py:\n  mapping: {}\nchromium-browser:\n  mapping: {}\n");
+        let cmdline = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        config.select_app("/usr/bin/python3.12", None, &cmdline(&["/usr/bin/python3", "/usr/bin/anki"]), "");
+        assert_eq!(config.active_profile.as_deref(), Some("anki"));
+        config.select_app("/usr/bin/python3.12", None, &cmdline(&["python3", "-u", "/opt/tool/run.py"]), "");
+        assert_eq!(config.active_profile.as_deref(), Some("/opt/tool/run.py"));
+        config.select_app("/usr/lib/chromium/chromium", None, &cmdline(&["/usr/bin/chromium-browser"]), "");
+        assert_eq!(config.active_profile.as_deref(), Some("chromium-browser"));
+    }
+
+    #[test]
+    fn terminal_foreground_takes_precedence() {
+        let mut config = ConfigFile::from_yaml("global:\n  mapping: {}\nalacritty:\n  mapping: {}\nvim:\n  mapping: {}\n");
+        config.select_app("/usr/bin/alacritty", None, &[], "Alacritty");
+        assert_eq!(config.active_profile.as_deref(), Some("alacritty"));
+        config.select_foreground("/usr/bin/vim.basic", &["vim".to_owned(), "notes.txt".to_owned()]);
+        assert_eq!(config.active_profile.as_deref(), Some("vim"));
+        config.select_foreground("/usr/bin/bash", &["-bash".to_owned()]);
+        assert_eq!(config.active_profile.as_deref(), Some("alacritty"));
+    }
+
+    #[test]
+    fn forced_profile_overrides_focus() {
+        let mut config = ConfigFile::from_yaml("global:\n  mapping: {}\nfirefox:\n  mapping: {}\nspotify:\n  mapping: {}\n");
+        config.select_app("/usr/lib/firefox/firefox", None, &[], "");
+        assert!(!config.force_profile(Some("unknown".to_owned())));
+        assert!(config.force_profile(Some("spotify".to_owned())));
+        assert_eq!(config.active_profile(), Some("spotify"));
+        config.select_app("/usr/bin/gimp", None, &[], "");
+        assert_eq!(config.active_profile(), Some("spotify"));
+        assert!(config.force_profile(None));
+        assert_eq!(config.active_profile(), None);
+    }
+
+    #[test]
+    fn plugins_arent_profiles() {
+        let mut config = ConfigFile::from_yaml("plugins:\n  - crown-plugin --verbose\nglobal:\n  mapping: {}\n");
+        assert_eq!(config.plugins(), vec!["crown-plugin --verbose".to_owned()]);
+        assert_eq!(config.config.as_ref().map(|c| c.app.len()), Some(1));
+    }
+}
@@ -0,0 +1,389 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::{remove_file, set_permissions, symlink_metadata, DirBuilder, Permissions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::thread::{JoinHandle, spawn};
+
+use crossbeam_channel::Sender;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::config::{Action, Modifier, Operation, RatchetMode};
+use crate::supervisor::Health;
+use crate::StateChanges;
+
+/// Returns `XDG_RUNTIME_DIR`, when it isn't set per-user directory with mode 0700 is created
+/// in temporary directory. Fails when that directory exists but isn't private to current user.
+pub(crate) fn runtime_dir() -> io::Result<PathBuf> {
+    if let Some(dir) = env::var_os("XDG_RUNTIME_DIR") {
+        return Ok(PathBuf::from(dir));
+    }
+    let uid = unsafe { libc::getuid() };
+    let dir = env::temp_dir().join(format!("crown-controller-{}", uid));
+    match DirBuilder::new().mode(0o700).create(&dir) {
+        Err(err) if err.kind() != io::ErrorKind::AlreadyExists => return Err(err),
+        _ => {}
+    }
+    let metadata = symlink_metadata(&dir)?;
+    if !metadata.is_dir() || metadata.uid() != uid || metadata.mode() & 0o077 != 0 {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                                  format!("{} isn't private directory of current user", dir.display())));
+    }
+    Ok(dir)
+}
+
+/// Returns path of control socket of the running daemon.
+pub(crate) fn socket_path() -> io::Result<PathBuf> {
+    runtime_dir().map(|dir| dir.join("crown-controller.sock"))
+}
+
+/// Requests accepted on control socket, one JSON object per line.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub(crate) enum Request {
+    Status,
+    /// Uses `profile` regardless of focused application, `null` restores automatic selection.
+    ForceProfile { profile: Option<String> },
+    /// Uses mapping of `layer` regardless of pressed modifiers, `null` restores automatic selection.
+    ForceLayer { layer: Option<Modifier> },
+    /// Changes ratchet mode until next application or modifier change.
+    SetRatchet { mode: RatchetMode },
+    /// Sets log levels, e.g. `info,hid=trace`.
+    SetLogLevel { filter: String },
+    /// Generates crown event, rotations are by `amount` notches.
+    Inject {
+        action: Action,
+        #[serde(default)]
+        modifiers: Option<Modifier>,
+        #[serde(default)]
+        amount: Option<i16>,
+    },
+    /// Streams `StateChanges` and executed operations until connection is closed.
+    Subscribe,
+    /// Restores device state and exits, used by `--replace`.
+    Shutdown,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub(crate) struct Status {
+    pub(crate) connected: bool,
+    /// Listeners that stopped at least once, by backend name.
+    pub(crate) health: BTreeMap<String, Health>,
+    /// Battery level in percent.
+    pub(crate) battery: Option<u8>,
+    pub(crate) app: Option<String>,
+    pub(crate) class: Option<String>,
+    pub(crate) profile: Option<String>,
+    pub(crate) forced_profile: Option<String>,
+    pub(crate) ratchet_mode: RatchetMode,
+    pub(crate) modifiers: Modifier,
+    pub(crate) layer: Modifier,
+    pub(crate) forced_layer: Option<Modifier>,
+    pub(crate) log_filter: String,
+}
+
+/// Lines sent back to control socket clients.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Reply<'a> {
+    Ok(bool),
+    Error(String),
+    Status(Status),
+    Event(&'a StateChanges),
+    Operation(&'a Operation),
+}
+
+impl Reply<'_> {
+    pub(crate) fn to_line(&self) -> String {
+        serde_json::to_string(self).unwrap_or_else(|e| json!({ "error": e.to_string() }).to_string())
+    }
+}
+
+/// Clients that subscribed to live stream of events.
+pub(crate) struct Subscribers {
+    senders: Vec<Sender<String>>,
+}
+
+impl Subscribers {
+    pub(crate) fn new() -> Subscribers {
+        Subscribers {
+            senders: Vec::new(),
+        }
+    }
+
+    pub(crate) fn add(&mut self, sender: Sender<String>) {
+        self.senders.push(sender);
+    }
+
+    pub(crate) fn broadcast(&mut self, reply: Reply) {
+        if !self.senders.is_empty() {
+            let line = reply.to_line();
+            self.senders.retain(|sender| sender.send(line.clone()).is_ok());
+        }
+    }
+}
+
+/// Accepts connections on control socket, requests needing daemon state are
+/// passed to main loop as `StateChanges::Control`.
+pub(crate) struct ControlHandler {
+    _listener: JoinHandle<()>,
+}
+
+impl ControlHandler {
+    pub fn new(sender: Sender<StateChanges>) -> std::io::Result<ControlHandler> {
+        let path = socket_path()?;
+        let _ = remove_file(&path);
+        let listener = UnixListener::bind(&path)?;
+        set_permissions(&path, Permissions::from_mode(0o600))?;
+
+        let _listener = spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let sender = sender.clone();
+                spawn(move || handle_client(stream, sender));
+            }
+        });
+
+        Ok(ControlHandler {
+            _listener,
+        })
+    }
+}
+
+fn injected_event(action: Action, modifiers: Option<Modifier>, amount: Option<i16>) -> StateChanges {
+    let modifiers = modifiers.map_or(0, u8::from);
+    let amount = amount.unwrap_or(1).saturating_abs();
+    match action {
+        Action::Touch => StateChanges::CrownTouched { modifiers },
+        Action::Release => StateChanges::CrownReleased { modifiers },
+        Action::Click => StateChanges::CrownClicked { modifiers },
+        Action::Left | Action::LeftPressed => StateChanges::CrownRotated {
+            modifiers,
+            amount: -amount,
+            notch_amount: -amount,
+            pressed: action == Action::LeftPressed,
+        },
+        Action::Right | Action::RightPressed => StateChanges::CrownRotated {
+            modifiers,
+            amount,
+            notch_amount: amount,
+            pressed: action == Action::RightPressed,
+        },
+    }
+}
+
+fn handle_client(stream: UnixStream, sender: Sender<StateChanges>) {
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(_) => return,
+    };
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(line) if line.trim().is_empty() => continue,
+            Ok(line) => line,
+            Err(_) => return,
+        };
+        debug!("Control request: {}", line);
+        let request = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => request,
+            Err(err) => {
+                if writeln!(writer, "{}", Reply::Error(err.to_string()).to_line()).is_err() {
+                    return;
+                }
+                continue;
+            }
+        };
+        let subscribe = matches!(request, Request::Subscribe);
+        let (reply_sender, reply_receiver) = crossbeam_channel::unbounded();
+        let change = match request {
+            Request::Inject { action, modifiers, amount } => {
+                let _ = reply_sender.send(Reply::Ok(true).to_line());
+                injected_event(action, modifiers, amount)
+            }
+            request => StateChanges::Control { request, reply: reply_sender },
+        };
+        if sender.send(change).is_err() {
+            return;
+        }
+        // Subscriptions receive replies until the main loop drops them
+        for reply in reply_receiver.iter() {
+            if writeln!(writer, "{}", reply).is_err() {
+                return;
+            }
+            if !subscribe {
+                break;
+            }
+        }
+    }
+}
+
+fn print_usage() {
+    println!("Usage: crown-controller ctl COMMAND
+Commands:
+    status                   show device, application, profile and layer
+    ratchet MODE             change ratchet mode (Free, Ratcheted or SmartShift)
+    profile [NAME]           force profile, without NAME profile follows focus again
+    layer [Shift|Alt|Ctrl]   force modifier layer, without argument layer follows keyboard again
+    inject ACTION [--modifiers Shift|Alt|Ctrl] [--amount N]
+                             generate crown event (touch, release, click, left, right,
+                             left_pressed, right_pressed)
+    log FILTER               set log levels, e.g. info,hid=trace
+    subscribe                stream events and executed operations
+    shutdown                 restore device state and stop daemon
+    send JSON                send raw request");
+}
+
+/// Runs `ctl` subcommand, returns process exit code.
+pub(crate) fn run_client(mut args: pico_args::Arguments) -> i32 {
+    let command: Option<String> = args.subcommand().unwrap_or(None);
+    let modifiers: Option<String> = args.opt_value_from_str("--modifiers").unwrap_or(None);
+    let amount: Option<i16> = args.opt_value_from_str("--amount").unwrap_or(None);
+    let free = args.free().unwrap_or_default();
+    let arg = free.first().cloned();
+
+    let request = match command.as_deref() {
+        Some("status") => json!({ "command": "status" }),
+        Some("profile") => json!({ "command": "force_profile", "profile": arg }),
+        Some("layer") => json!({ "command": "force_layer", "layer": arg }),
+        Some("log") if arg.is_some() => json!({ "command": "set_log_level", "filter": arg }),
+        Some("ratchet") if arg.is_some() => json!({ "command": "set_ratchet", "mode": arg }),
+        Some("inject") if arg.is_some() =>
+            json!({ "command": "inject", "action": arg, "modifiers": modifiers, "amount": amount }),
+        Some("subscribe") => json!({ "command": "subscribe" }),
+        Some("shutdown") => json!({ "command": "shutdown" }),
+        Some("send") if arg.is_some() => match serde_json::from_str(arg.as_deref().unwrap_or_default()) {
+            Ok(request) => request,
+            Err(err) => {
+                println!("Invalid JSON: {}", err);
+                return 2;
+            }
+        },
+        _ => {
+            print_usage();
+            return 2;
+        }
+    };
+
+    let path = match socket_path() {
+        Ok(path) => path,
+        Err(err) => {
+            println!("Can't find control socket: {}", err);
+            return 1;
+        }
+    };
+    let mut stream = match UnixStream::connect(&path) {
+        Ok(stream) => stream,
+        Err(err) => {
+            println!("Can't connect to {}: {}", path.display(), err);
+            return 1;
+        }
+    };
+    if writeln!(stream, "{}", request).is_err() {
+        return 1;
+    }
+    let subscribe = command.as_deref() == Some("subscribe");
+    let mut status = 1;
+    for line in BufReader::new(stream).lines().map_while(Result::ok) {
+        println!("{}", line);
+        status = if line.starts_with(r#"{"error""#) { 1 } else { 0 };
+        if !subscribe {
+            break;
+        }
+    }
+    status
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs `handle_client` on one end of socket pair and answers its requests
+    /// like the main loop does, returns the other end.
+    fn client() -> BufReader<UnixStream> {
+        let (stream, daemon) = UnixStream::pair().unwrap();
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        spawn(move || handle_client(daemon, sender));
+        spawn(move || {
+            for change in receiver.iter() {
+                let reply = match change {
+                    StateChanges::Control { request, reply } => (request, reply),
+                    StateChanges::CrownRotated { amount, .. } => {
+                        assert_eq!(amount, -2);
+                        continue;
+                    }
+                    change => panic!("Unexpected {:?}", change),
+                };
+                let line = match reply.0 {
+                    Request::Status => Reply::Status(Status {
+                        connected: true,
+                        health: BTreeMap::new(),
+                        battery: Some(80),
+                        app: Some("/usr/bin/gimp".to_owned()),
+                        class: None,
+                        profile: Some("gimp".to_owned()),
+                        forced_profile: None,
+                        ratchet_mode: RatchetMode::Free,
+                        modifiers: Modifier::None,
+                        layer: Modifier::Shift,
+                        forced_layer: Some(Modifier::Shift),
+                        log_filter: "info".to_owned(),
+                    }).to_line(),
+                    Request::ForceProfile { profile } if profile.as_deref() == Some("gimp") => Reply::Ok(true).to_line(),
+                    Request::ForceProfile { .. } => Reply::Error("Unknown profile".to_owned()).to_line(),
+                    Request::ForceLayer { layer } => Reply::Ok(layer.is_none()).to_line(),
+                    Request::SetRatchet { .. } => Reply::Ok(true).to_line(),
+                    Request::SetLogLevel { filter } => Reply::Ok(filter == "info,hid=trace").to_line(),
+                    Request::Subscribe => {
+                        let _ = reply.1.send(Reply::Ok(true).to_line());
+                        Reply::Event(&StateChanges::CrownClicked { modifiers: 0 }).to_line()
+                    }
+                    Request::Shutdown => Reply::Ok(true).to_line(),
+                    Request::Inject { .. } => panic!("Inject isn't passed to main loop"),
+                };
+                let _ = reply.1.send(line);
+            }
+        });
+        BufReader::new(stream)
+    }
+
+    fn request(client: &mut BufReader<UnixStream>, request: &str) -> String {
+        writeln!(client.get_mut(), "{}", request).unwrap();
+        let mut line = String::new();
+        client.read_line(&mut line).unwrap();
+        line.trim_end().to_owned()
+    }
+
+    #[test]
+    fn requests_get_one_reply_line() {
+        let mut client = client();
+        let status: serde_json::Value = serde_json::from_str(&request(&mut client, r#"{"command": "status"}"#)).unwrap();
+        assert_eq!(status["status"]["profile"], "gimp");
+        assert_eq!(status["status"]["layer"], "Shift");
+        assert_eq!(status["status"]["log_filter"], "info");
+        assert_eq!(request(&mut client, r#"{"command": "force_profile", "profile": "nope"}"#),
+                   r#"{"error":"Unknown profile"}"#);
+        assert_eq!(request(&mut client, r#"{"command": "force_profile", "profile": "gimp"}"#), r#"{"ok":true}"#);
+        assert_eq!(request(&mut client, r#"{"command": "force_layer", "layer": null}"#), r#"{"ok":true}"#);
+        assert_eq!(request(&mut client, r#"{"command": "set_log_level", "filter": "info,hid=trace"}"#), r#"{"ok":true}"#);
+        assert_eq!(request(&mut client, r#"{"command": "inject", "action": "left", "amount": 2}"#), r#"{"ok":true}"#);
+        assert_eq!(request(&mut client, r#"{"command": "shutdown"}"#), r#"{"ok":true}"#);
+        assert!(request(&mut client, r#"{"command": "unknown"}"#).starts_with(r#"{"error":"unknown variant"#));
+    }
+
+    #[test]
+    fn subscription_streams_replies() {
+        let mut client = client();
+        assert_eq!(request(&mut client, r#"{"command": "subscribe"}"#), r#"{"ok":true}"#);
+        let mut line = String::new();
+        client.read_line(&mut line).unwrap();
+        assert_eq!(line.trim_end(), r#"{"event":{"CrownClicked":{"modifiers":0}}}"#);
+    }
+
+    #[test]
+    fn injected_rotation_saturates() {
+        let event = injected_event(Action::Left, None, Some(i16::MIN));
+        assert!(matches!(event, StateChanges::CrownRotated { amount: -32767, notch_amount: -32767, .. }));
+    }
+}
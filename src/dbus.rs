@@ -0,0 +1,258 @@
+use std::sync::{Arc, Mutex};
+
+use crossbeam_channel::Sender;
+use zbus::blocking::connection::Builder;
+use zbus::blocking::object_server::InterfaceRef;
+use zbus::blocking::Connection;
+use zbus::object_server::SignalEmitter;
+use zbus::{fdo, interface};
+
+use crate::config::{Modifier, RatchetMode};
+use crate::control::{Request, Status};
+use crate::StateChanges;
+
+const SERVICE_NAME: &str = "org.prefiks.CrownController";
+const OBJECT_PATH: &str = "/org/prefiks/CrownController";
+
+struct CrownService {
+    sender: Sender<StateChanges>,
+    status: Arc<Mutex<Option<Status>>>,
+}
+
+impl CrownService {
+    /// Passes `request` to main loop and waits for its reply.
+    fn request(&self, request: Request) -> fdo::Result<()> {
+        debug!("D-Bus request: {:?}", request);
+        let (reply, receiver) = crossbeam_channel::bounded(1);
+        self.sender.send(StateChanges::Control { request, reply }).
+            map_err(|e| fdo::Error::Failed(e.to_string()))?;
+        let line = receiver.recv().map_err(|e| fdo::Error::Failed(e.to_string()))?;
+        match serde_json::from_str::<serde_json::Value>(&line).ok().as_ref().and_then(|v| v.get("error")) {
+            Some(error) => Err(fdo::Error::Failed(error.as_str().unwrap_or_default().to_owned())),
+            None => Ok(()),
+        }
+    }
+
+    fn field<T: Default>(&self, f: impl FnOnce(&Status) -> T) -> T {
+        self.status.lock().ok().and_then(|status| status.as_ref().map(f)).unwrap_or_default()
+    }
+}
+
+#[interface(name = "org.prefiks.CrownController")]
+impl CrownService {
+    /// Changes ratchet mode (`Free`, `Ratcheted` or `SmartShift`) until next application or modifier change.
+    fn set_ratchet_mode(&self, mode: &str) -> fdo::Result<()> {
+        let mode: RatchetMode = serde_json::from_value(serde_json::Value::from(mode)).
+            map_err(|e| fdo::Error::InvalidArgs(e.to_string()))?;
+        self.request(Request::SetRatchet { mode })
+    }
+
+    /// Uses `profile` regardless of focused application, empty name restores automatic selection.
+    fn switch_profile(&self, profile: &str) -> fdo::Result<()> {
+        let profile = Some(profile.to_owned()).filter(|p| !p.is_empty());
+        self.request(Request::ForceProfile { profile })
+    }
+
+    #[zbus(property)]
+    fn active_profile(&self) -> String {
+        self.field(|s| s.profile.clone().unwrap_or_default())
+    }
+
+    #[zbus(property)]
+    fn active_application(&self) -> String {
+        self.field(|s| s.app.clone().unwrap_or_default())
+    }
+
+    #[zbus(property)]
+    fn ratchet_mode(&self) -> String {
+        self.field(|s| format!("{:?}", s.ratchet_mode))
+    }
+
+    #[zbus(property)]
+    fn layer(&self) -> String {
+        self.field(|s| format!("{:?}", s.layer))
+    }
+
+    /// Battery level in percent, -1 when it's not known.
+    #[zbus(property)]
+    fn battery(&self) -> i32 {
+        self.status.lock().ok().and_then(|s| s.as_ref()?.battery).map_or(-1, i32::from)
+    }
+
+    #[zbus(property)]
+    fn connected(&self) -> bool {
+        self.field(|s| s.connected)
+    }
+
+    #[zbus(signal)]
+    async fn rotated(emitter: &SignalEmitter<'_>, amount: i32, pressed: bool, layer: &str) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn clicked(emitter: &SignalEmitter<'_>, layer: &str) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn touched(emitter: &SignalEmitter<'_>, layer: &str) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn released(emitter: &SignalEmitter<'_>, layer: &str) -> zbus::Result<()>;
+}
+
+/// Exposes daemon state on session bus as `org.prefiks.CrownController`.
+pub(crate) struct DbusHandler {
+    _conn: Connection,
+    iface: InterfaceRef<CrownService>,
+    status: Arc<Mutex<Option<Status>>>,
+    last_status: Option<Status>,
+}
+
+impl DbusHandler {
+    pub fn new(sender: Sender<StateChanges>) -> zbus::Result<DbusHandler> {
+        let status = Arc::new(Mutex::new(None));
+        let service = CrownService {
+            sender,
+            status: status.clone(),
+        };
+        let conn = Builder::session()?.
+            name(SERVICE_NAME)?.
+            serve_at(OBJECT_PATH, service)?.
+            build()?;
+        let iface = conn.object_server().interface::<_, CrownService>(OBJECT_PATH)?;
+
+        Ok(DbusHandler {
+            _conn: conn,
+            iface,
+            status,
+            last_status: None,
+        })
+    }
+
+    /// Stores new daemon state and emits `PropertiesChanged` for properties that changed.
+    pub fn update(&mut self, status: Status) {
+        if self.last_status.as_ref() == Some(&status) {
+            return;
+        }
+        let last = self.last_status.replace(status.clone());
+        if let Ok(mut shared) = self.status.lock() {
+            *shared = Some(status.clone());
+        }
+        let last = match last {
+            Some(last) => last,
+            None => return,
+        };
+        let emitter = self.iface.signal_emitter();
+        let res = zbus::block_on(async {
+            let iface = self.iface.get();
+            if last.profile != status.profile {
+                iface.active_profile_changed(emitter).await?;
+            }
+            if last.app != status.app {
+                iface.active_application_changed(emitter).await?;
+            }
+            if last.ratchet_mode != status.ratchet_mode {
+                iface.ratchet_mode_changed(emitter).await?;
+            }
+            if last.layer != status.layer {
+                iface.layer_changed(emitter).await?;
+            }
+            if last.battery != status.battery {
+                iface.battery_changed(emitter).await?;
+            }
+            if last.connected != status.connected {
+                iface.connected_changed(emitter).await?;
+            }
+            zbus::Result::Ok(())
+        });
+        if let Err(err) = res {
+            warn!("Can't emit D-Bus property change: {}", err);
+        }
+    }
+
+    /// Emits signal for crown event, `forced_layer` overrides layer from pressed modifiers.
+    pub fn crown_event(&self, change: &StateChanges, forced_layer: Option<Modifier>) {
+        let layer = |modifiers: u8| format!("{:?}", forced_layer.unwrap_or(Modifier::from(modifiers)));
+        let emitter = self.iface.signal_emitter();
+        let res = zbus::block_on(async {
+            match *change {
+                StateChanges::CrownRotated { modifiers, amount, pressed, .. } =>
+                    CrownService::rotated(emitter, amount.into(), pressed, &layer(modifiers)).await,
+                StateChanges::CrownClicked { modifiers } => CrownService::clicked(emitter, &layer(modifiers)).await,
+                StateChanges::CrownTouched { modifiers } => CrownService::touched(emitter, &layer(modifiers)).await,
+                StateChanges::CrownReleased { modifiers } => CrownService::released(emitter, &layer(modifiers)).await,
+                _ => Ok(()),
+            }
+        });
+        if let Err(err) = res {
+            warn!("Can't emit D-Bus signal: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::spawn;
+
+    use super::*;
+
+    fn service() -> (CrownService, crossbeam_channel::Receiver<StateChanges>) {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        (CrownService { sender, status: Arc::new(Mutex::new(None)) }, receiver)
+    }
+
+    #[test]
+    fn properties_from_status() {
+        let (service, _receiver) = service();
+        assert_eq!((service.active_profile(), service.battery(), service.connected()), (String::new(), -1, false));
+
+        *service.status.lock().unwrap() = Some(Status {
+            connected: true,
+            health: Default::default(),
+            battery: Some(80),
+            app: Some("/usr/bin/firefox".to_owned()),
+            class: None,
+            profile: Some("firefox".to_owned()),
+            forced_profile: None,
+            ratchet_mode: RatchetMode::SmartShift,
+            modifiers: Modifier::None,
+            layer: Modifier::Shift,
+            forced_layer: Some(Modifier::Shift),
+            log_filter: "info".to_owned(),
+        });
+        assert_eq!(service.active_profile(), "firefox");
+        assert_eq!(service.active_application(), "/usr/bin/firefox");
+        assert_eq!((service.ratchet_mode(), service.layer()), ("SmartShift".to_owned(), "Shift".to_owned()));
+        assert_eq!((service.battery(), service.connected()), (80, true));
+    }
+
+    #[test]
+    fn methods_send_requests() {
+        let (service, receiver) = service();
+        let main_loop = spawn(move || {
+            let mut requests = Vec::new();
+            for change in receiver {
+                if let StateChanges::Control { request, reply } = change {
+                    let line = if matches!(request, Request::ForceProfile { profile: Some(_) }) {
+                        r#"{"error":"Unknown profile"}"#
+                    } else {
+                        r#"{"ok":true}"#
+                    };
+                    requests.push(request);
+                    let _ = reply.send(line.to_owned());
+                }
+            }
+            requests
+        });
+
+        assert!(service.set_ratchet_mode("SmartShift").is_ok());
+        assert!(matches!(service.set_ratchet_mode("Loose"), Err(fdo::Error::InvalidArgs(_))));
+        assert!(service.switch_profile("").is_ok());
+        assert!(matches!(service.switch_profile("gimp"), Err(fdo::Error::Failed(ref e)) if e == "Unknown profile"));
+        drop(service);
+
+        let requests = main_loop.join().unwrap();
+        assert!(matches!(requests.as_slice(), [
+            Request::SetRatchet { mode: RatchetMode::SmartShift },
+            Request::ForceProfile { profile: None },
+            Request::ForceProfile { profile: Some(_) },
+        ]));
+    }
+}
@@ -0,0 +1,146 @@
+use crate::config::{describe_operations, Action, ConfigFile, Modifier};
+
+fn print_usage() {
+    println!("Usage: crown-controller explain --app PATH [OPTIONS]
+Options:
+    --app PATH               program of focused window
+    --cmdline \"ARGS\"         its command line, defaults to PATH
+    --sandbox-id ID          Flatpak application id or Snap name
+    --class CLASS            window class or Wayland app_id
+    --foreground PATH        foreground program when focused window is terminal
+    --profile NAME           forced profile
+    --modifiers None|Shift|Alt|Ctrl
+                             pressed modifier or forced layer, None by default
+    --action ACTION          touch, release, click, left, right, left_pressed or right_pressed,
+                             right by default");
+}
+
+fn parse_modifier(s: &str) -> Result<Modifier, String> {
+    match s.to_lowercase().as_str() {
+        "none" => Ok(Modifier::None),
+        "shift" => Ok(Modifier::Shift),
+        "alt" => Ok(Modifier::Alt),
+        "ctrl" => Ok(Modifier::Ctrl),
+        _ => Err(format!("Unknown modifier {}", s)),
+    }
+}
+
+fn parse_action(s: &str) -> Result<Action, String> {
+    serde_json::from_value(serde_json::Value::from(s.to_lowercase())).map_err(|_| format!("Unknown action {}", s))
+}
+
+/// Focused application and crown event to resolve.
+struct Simulation {
+    app: String,
+    cmdline: Vec<String>,
+    sandbox_id: Option<String>,
+    class: Option<String>,
+    foreground: Option<String>,
+    profile: Option<String>,
+    modifiers: Modifier,
+    action: Action,
+}
+
+fn parse(mut args: pico_args::Arguments) -> Result<Simulation, pico_args::Error> {
+    let app: String = args.opt_value_from_str("--app")?.unwrap_or_default();
+    let cmdline: Option<String> = args.opt_value_from_str("--cmdline")?;
+    let simulation = Simulation {
+        cmdline: cmdline.map_or_else(|| Some(app.clone()).into_iter().filter(|a| !a.is_empty()).collect(),
+                                     |c| c.split_whitespace().map(str::to_owned).collect()),
+        app,
+        sandbox_id: args.opt_value_from_str("--sandbox-id")?,
+        class: args.opt_value_from_str("--class")?,
+        foreground: args.opt_value_from_str("--foreground")?,
+        profile: args.opt_value_from_str("--profile")?,
+        modifiers: args.opt_value_from_fn("--modifiers", parse_modifier)?.unwrap_or(Modifier::None),
+        action: args.opt_value_from_fn("--action", parse_action)?.unwrap_or(Action::Right),
+    };
+    args.finish()?;
+    Ok(simulation)
+}
+
+/// Runs `explain` subcommand, resolving profile, operations and ratchet mode for simulated
+/// crown event like the daemon does. Returns process exit code.
+pub(crate) fn run(args: pico_args::Arguments) -> i32 {
+    let simulation = match parse(args) {
+        Ok(s) if !s.app.is_empty() || s.sandbox_id.is_some() || s.class.is_some() || s.profile.is_some() => s,
+        Ok(_) => {
+            print_usage();
+            return 2;
+        }
+        Err(err) => {
+            println!("{}", err);
+            print_usage();
+            return 2;
+        }
+    };
+
+    let mut config = ConfigFile::new();
+    config.select_app(&simulation.app, simulation.sandbox_id.as_deref(), &simulation.cmdline,
+                      simulation.class.as_deref().unwrap_or_default());
+    if let Some(ref foreground) = simulation.foreground {
+        config.select_foreground(foreground, std::slice::from_ref(foreground));
+    }
+    if simulation.profile.is_some() && !config.force_profile(simulation.profile) {
+        println!("Forced profile isn't defined");
+    }
+
+    for line in config.explain(simulation.modifiers, simulation.action) {
+        println!("{}", line);
+    }
+    // Same lookups as the daemon does for crown events
+    match config.get_mapping_for_modifiers(simulation.modifiers, simulation.action) {
+        Some((profile, mapping)) => println!("Result: operations from profile {}: {}", profile,
+            describe_operations(ConfigFile::get_actions_from_mapping(&mapping, simulation.action).unwrap_or_default())),
+        None => println!("Result: no operations"),
+    }
+    println!("Result: ratchet mode {:?}", config.ratchet_mode_for_modifier(simulation.modifiers));
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsString;
+
+    use super::*;
+
+    fn args(args: &[&str]) -> pico_args::Arguments {
+        pico_args::Arguments::from_vec(args.iter().map(OsString::from).collect())
+    }
+
+    #[test]
+    fn simulation_defaults() {
+        let simulation = parse(args(&["--app", "/usr/bin/kitty"])).unwrap();
+        assert_eq!(simulation.cmdline, vec!["/usr/bin/kitty"]);
+        assert_eq!(simulation.modifiers, Modifier::None);
+        assert_eq!(simulation.action, Action::Right);
+        assert!(simulation.sandbox_id.is_none() && simulation.foreground.is_none() && simulation.profile.is_none());
+
+        let simulation = parse(args(&["--class", "kitty"])).unwrap();
+        assert!(simulation.app.is_empty() && simulation.cmdline.is_empty());
+        assert_eq!(simulation.class.as_deref(), Some("kitty"));
+    }
+
+    #[test]
+    fn simulation_options() {
+        let simulation = parse(args(&["--app", "/usr/bin/python3", "--cmdline", "python3  /opt/tool.py -v",
+                                      "--sandbox-id", "org.gimp.GIMP", "--foreground", "vim", "--profile", "gimp",
+                                      "--modifiers", "CTRL", "--action", "Right_Pressed"])).unwrap();
+        assert_eq!(simulation.cmdline, vec!["python3", "/opt/tool.py", "-v"]);
+        assert_eq!(simulation.sandbox_id.as_deref(), Some("org.gimp.GIMP"));
+        assert_eq!(simulation.foreground.as_deref(), Some("vim"));
+        assert_eq!(simulation.profile.as_deref(), Some("gimp"));
+        assert_eq!(simulation.modifiers, Modifier::Ctrl);
+        assert_eq!(simulation.action, Action::RightPressed);
+    }
+
+    #[test]
+    fn invalid_arguments_are_rejected() {
+        let err = parse(args(&["--app", "kitty", "--modifiers", "super"])).err().unwrap();
+        assert!(err.to_string().contains("Unknown modifier super"));
+        let err = parse(args(&["--app", "kitty", "--action", "spin"])).err().unwrap();
+        assert!(err.to_string().contains("Unknown action spin"));
+        assert!(parse(args(&["--app", "kitty", "--verbose"])).is_err());
+        assert_eq!(run(args(&["--modifiers", "shift"])), 2);
+    }
+}
@@ -1,21 +1,66 @@
-use std::fs::{File, OpenOptions};
-use std::io::{Read, Write};
+use std::collections::VecDeque;
+use std::fs::OpenOptions;
+use std::io::{self, ErrorKind, Read, Write};
 use std::os::unix::fs::OpenOptionsExt;
 use std::os::unix::io::AsRawFd;
 use std::sync::Arc;
-use std::thread::spawn;
+use std::time::{Duration, Instant};
 
 use crossbeam_channel::{Receiver, Sender};
-use libc;
 use mio::{Events, Interest, Poll, Token, Waker};
 use mio::unix::SourceFd;
 
+use crate::backend::InputSource;
+use crate::config::RatchetMode;
+use crate::supervisor::supervise;
 use crate::StateChanges;
 
 #[derive(Debug)]
 enum CrownCommands {
     EnableRatchet,
     DisableRatchet,
+    SmartShift { threshold: u16 },
+    EndStop,
+    /// Switches crown back to ratcheted mode, `done` is notified afterwards.
+    Restore { done: Sender<()> },
+}
+
+const SMART_SHIFT_WINDOW: Duration = Duration::from_millis(100);
+const SMART_SHIFT_RESTORE: Duration = Duration::from_millis(300);
+
+/// Tracks rotation speed for smart shift mode, crown switches to free mode
+/// when sum of rotation amounts in `SMART_SHIFT_WINDOW` exceeds `threshold`
+/// and back to ratcheted after `SMART_SHIFT_RESTORE` without fast rotation.
+struct SmartShift {
+    threshold: u16,
+    samples: VecDeque<(Instant, u16)>,
+    restore_at: Option<Instant>,
+}
+
+impl SmartShift {
+    fn new(threshold: u16) -> SmartShift {
+        SmartShift {
+            threshold,
+            samples: VecDeque::new(),
+            restore_at: None,
+        }
+    }
+
+    /// Records rotation and returns `true` if crown rotates fast enough to free it.
+    fn rotated(&mut self, amount: i16) -> bool {
+        let now = Instant::now();
+        self.samples.push_back((now, amount.unsigned_abs()));
+        while self.samples.front().is_some_and(|(t, _)| now.duration_since(*t) > SMART_SHIFT_WINDOW) {
+            self.samples.pop_front();
+        }
+        let speed: u16 = self.samples.iter().map(|(_, a)| *a).sum();
+        if speed >= self.threshold {
+            self.restore_at = Some(now + SMART_SHIFT_RESTORE);
+            true
+        } else {
+            false
+        }
+    }
 }
 
 pub(crate) struct HidHandler {
@@ -24,11 +69,12 @@ pub(crate) struct HidHandler {
 }
 
 impl HidHandler {
-    pub fn new(sender: Sender<StateChanges>, debug_enabled: bool) -> std::io::Result<HidHandler> {
+    pub fn new(sender: Sender<StateChanges>) -> io::Result<HidHandler> {
         let (my_sender, my_receiver) = crossbeam_channel::unbounded();
-        let poll = Poll::new()?;
+        let mut poll = Poll::new()?;
         let waker = Arc::new(Waker::new(poll.registry(), Token(10))?);
-        let _x = spawn(move || hid_listener(sender, my_receiver, poll, debug_enabled));
+        let listener_sender = sender.clone();
+        let _x = supervise("hid", sender, move || hid_listener(&listener_sender, &my_receiver, &mut poll))?;
 
         Ok(HidHandler {
             my_sender,
@@ -36,15 +82,96 @@ impl HidHandler {
         })
     }
 
-    pub fn enable_ratcher(&self) {
-        if self.my_sender.send(CrownCommands::EnableRatchet).is_ok() {
+    fn send(&self, command: CrownCommands) {
+        if self.my_sender.send(command).is_ok() {
             let _ = self.waker.wake();
         }
     }
+}
+
+impl InputSource for HidHandler {
+    /// In smart shift mode crown switches to free mode automatically when it rotates fast.
+    fn set_ratchet_mode(&self, mode: RatchetMode, smart_shift_threshold: u16) {
+        self.send(match mode {
+            RatchetMode::Ratcheted => CrownCommands::EnableRatchet,
+            RatchetMode::Free => CrownCommands::DisableRatchet,
+            RatchetMode::SmartShift => CrownCommands::SmartShift { threshold: smart_shift_threshold },
+        });
+    }
 
-    pub fn disable_ratcher(&self) {
-        if self.my_sender.send(CrownCommands::DisableRatchet).is_ok() {
-            let _ = self.waker.wake();
+    fn end_stop(&self) {
+        self.send(CrownCommands::EndStop);
+    }
+
+    fn restore(&self) {
+        let (done, receiver) = crossbeam_channel::bounded(1);
+        self.send(CrownCommands::Restore { done });
+        let _ = receiver.recv_timeout(Duration::from_secs(1));
+    }
+}
+
+const DEVICE_INDEX: u8 = 0x03;
+const SOFTWARE_ID: u8 = 0x04;
+const UNIFIED_BATTERY: u16 = 0x1004;
+const BATTERY_STATUS: u16 = 0x1000;
+const BATTERY_REFRESH: Duration = Duration::from_secs(600);
+
+/// Reads battery level through HID++ `UNIFIED_BATTERY` feature, or `BATTERY_STATUS`
+/// on older devices. Index of the feature is looked up through root feature first.
+struct Battery {
+    probing: Option<u16>,
+    feature_index: Option<u8>,
+    status_function: u8,
+    refresh_at: Option<Instant>,
+}
+
+impl Battery {
+    fn new() -> Battery {
+        Battery {
+            probing: None,
+            feature_index: None,
+            status_function: 0,
+            refresh_at: None,
+        }
+    }
+
+    fn get_feature(&mut self, handle: &mut impl Write, feature: u16) {
+        self.probing = Some(feature);
+        let [hi, lo] = feature.to_be_bytes();
+        let _ = handle.write_all(&[0x10, DEVICE_INDEX, 0x00, SOFTWARE_ID, hi, lo, 0]);
+    }
+
+    fn probe(&mut self, handle: &mut impl Write) {
+        self.feature_index = None;
+        self.get_feature(handle, UNIFIED_BATTERY);
+    }
+
+    fn refresh(&mut self, handle: &mut impl Write) {
+        if let Some(index) = self.feature_index {
+            let _ = handle.write_all(&[0x10, DEVICE_INDEX, index, self.status_function << 4 | SOFTWARE_ID, 0, 0, 0]);
+        }
+        self.refresh_at = Some(Instant::now() + BATTERY_REFRESH);
+    }
+
+    /// Handles HID++ message, returns battery level in percent when device reported it.
+    fn handle(&mut self, handle: &mut impl Write, data: &[u8]) -> Option<u8> {
+        match data {
+            [0x11, _, 0x00, SOFTWARE_ID, index, ..] => {
+                match (self.probing.take(), *index) {
+                    (Some(UNIFIED_BATTERY), 0) => self.get_feature(handle, BATTERY_STATUS),
+                    (Some(feature), index) if index != 0 => {
+                        self.feature_index = Some(index);
+                        self.status_function = if feature == UNIFIED_BATTERY { 1 } else { 0 };
+                        self.refresh(handle);
+                    }
+                    _ => {}
+                }
+                None
+            }
+            // Level is sent in reply to status request and in events (function 0, software id 0)
+            [0x11, _, index, function, level, ..] if Some(*index) == self.feature_index && *level != 0 &&
+                (*function == 0 || *function == self.status_function << 4 | SOFTWARE_ID) => Some(*level),
+            _ => None,
         }
     }
 }
@@ -81,7 +208,7 @@ fn decode_event(data: &[u8]) -> CrownEvent {
     }
 }
 
-fn switch_ratcher(handle: &mut File, enabled: bool) -> () {
+fn switch_ratcher(handle: &mut impl Write, enabled: bool) {
     if enabled {
         let _ = handle.write_all(&[0x11, 0x03, 0x12, 0x21, 0x02, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
     } else {
@@ -89,88 +216,278 @@ fn switch_ratcher(handle: &mut File, enabled: bool) -> () {
     }
 }
 
+/// Ratchet mode requested by the main loop together with temporary changes
+/// made by end stop feedback and smart shift.
+struct Ratchet {
+    enabled: bool,
+    end_stop_until: Option<Instant>,
+    smart_shift: Option<SmartShift>,
+}
+
+impl Ratchet {
+    fn new() -> Ratchet {
+        Ratchet {
+            enabled: true,
+            end_stop_until: None,
+            smart_shift: None,
+        }
+    }
+
+    fn handle(&mut self, command: CrownCommands, handle: &mut impl Write) {
+        match command {
+            CrownCommands::EnableRatchet => {
+                self.smart_shift = None;
+                self.enabled = true;
+                switch_ratcher(handle, true);
+            }
+            CrownCommands::DisableRatchet => {
+                self.smart_shift = None;
+                self.enabled = false;
+                switch_ratcher(handle, false);
+            }
+            CrownCommands::SmartShift { threshold } => {
+                self.smart_shift = Some(SmartShift::new(threshold));
+                self.enabled = true;
+                switch_ratcher(handle, true);
+            }
+            CrownCommands::EndStop if self.end_stop_until.is_none() => {
+                self.end_stop_until = Some(Instant::now() + Duration::from_millis(80));
+                switch_ratcher(handle, !self.enabled);
+            }
+            CrownCommands::EndStop => {}
+            CrownCommands::Restore { done } => {
+                *self = Ratchet::new();
+                switch_ratcher(handle, true);
+                let _ = done.send(());
+            }
+        }
+    }
+}
+
+/// Handles all queued commands, wakeups sent for several commands can be merged into one event.
+fn handle_commands(ratchet: &mut Ratchet, receiver: &Receiver<CrownCommands>, handle: &mut impl Write) {
+    while let Ok(command) = receiver.try_recv() {
+        debug!("Mode events: {:?}", command);
+        ratchet.handle(command, handle);
+    }
+}
+
+/// Drops commands sent while listener wasn't running, main loop sends ratchet mode again
+/// after connecting. Device isn't open, so `Restore` is done right away.
+fn discard_commands(receiver: &Receiver<CrownCommands>) {
+    while let Ok(command) = receiver.try_recv() {
+        if let CrownCommands::Restore { done } = command {
+            let _ = done.send(());
+        }
+    }
+}
 
-fn hid_listener(sender: Sender<StateChanges>, receiver: Receiver<CrownCommands>, mut poll: Poll,
-                debug_enabled: bool) -> ()
-{
-    let mut ratchet_enabled = true;
+/// Reads crown events until device can't be read anymore.
+fn hid_listener(sender: &Sender<StateChanges>, receiver: &Receiver<CrownCommands>, poll: &mut Poll) -> io::Result<()> {
+    let mut ratchet = Ratchet::new();
     let mut modifiers = 0;
     let mut had_rotation = false;
+    let mut battery = Battery::new();
+    discard_commands(receiver);
 
-    if let Ok(Some(dev_path)) = crate::udev::find_hidraw_device(0x46D, 0x4066) {
-        let mut fh = OpenOptions::new().
-            read(true).
-            write(true).
-            custom_flags(libc::O_NONBLOCK).
-            open(dev_path).unwrap();
+    let dev_path = crate::udev::find_hidraw_device(0x46D, 0x4066)?.
+        ok_or_else(|| io::Error::new(ErrorKind::NotFound, "Crown device not found"))?;
+    let mut fh = OpenOptions::new().
+        read(true).
+        write(true).
+        custom_flags(libc::O_NONBLOCK).
+        open(&dev_path).
+        map_err(|e| io::Error::new(e.kind(), format!("Can't open {:?}: {}", dev_path, e)))?;
 
-        let hidraw_token = Token(0);
-        let mut events = Events::with_capacity(2);
+    let hidraw_token = Token(0);
+    let mut events = Events::with_capacity(2);
 
-        poll.registry().register(&mut SourceFd(&fh.as_raw_fd()), hidraw_token, Interest::READABLE).unwrap();
+    poll.registry().register(&mut SourceFd(&fh.as_raw_fd()), hidraw_token, Interest::READABLE)?;
+    let _ = sender.send(StateChanges::DeviceConnected { connected: true });
 
-        let mut buf = [0u8; 1000];
-        switch_ratcher(&mut fh, true);
+    let mut buf = [0u8; 1000];
+    switch_ratcher(&mut fh, true);
+    battery.probe(&mut fh);
 
-        loop {
-            let _ = poll.poll(&mut events, None);
-            for event in &events {
-                if event.token() != hidraw_token {
-                    if let Ok(command) = receiver.try_recv() {
-                        if debug_enabled {
-                            println!("Mode events: {:?}", command);
-                        }
-                        match command {
-                            CrownCommands::EnableRatchet => {
-                                ratchet_enabled = true;
-                                switch_ratcher(&mut fh, true);
-                            }
-                            CrownCommands::DisableRatchet => {
-                                ratchet_enabled = false;
-                                switch_ratcher(&mut fh, false);
-                            }
+    loop {
+        let deadline = ratchet.end_stop_until.into_iter().
+            chain(ratchet.smart_shift.as_ref().and_then(|s| s.restore_at)).
+            chain(battery.refresh_at).
+            min();
+        if let Err(err) = poll.poll(&mut events, deadline.map(|t| t.saturating_duration_since(Instant::now()))) {
+            if err.kind() != ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+        if let Some(shift) = ratchet.smart_shift.as_mut().filter(|s| s.restore_at.is_some_and(|t| t <= Instant::now())) {
+            shift.restore_at = None;
+            debug!("Smart shift: ratcheted");
+            ratchet.enabled = true;
+            if ratchet.end_stop_until.is_none() {
+                switch_ratcher(&mut fh, true);
+            }
+        }
+        if battery.refresh_at.is_some_and(|t| t <= Instant::now()) {
+            battery.refresh(&mut fh);
+        }
+        if ratchet.end_stop_until.is_some_and(|t| t <= Instant::now()) {
+            ratchet.end_stop_until = None;
+            switch_ratcher(&mut fh, ratchet.enabled);
+        }
+        for event in &events {
+            if event.token() != hidraw_token {
+                handle_commands(&mut ratchet, receiver, &mut fh);
+            } else {
+                loop {
+                    let size = match fh.read(buf.as_mut()) {
+                        Ok(size) => size,
+                        Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                        Err(err) => {
+                            let _ = poll.registry().deregister(&mut SourceFd(&fh.as_raw_fd()));
+                            return Err(io::Error::new(err.kind(), format!("Can't read crown events: {}", err)));
                         }
+                    };
+                    let slice = &buf[0..size];
+                    if let Some(level) = battery.handle(&mut fh, slice) {
+                        info!("Battery level: {}%", level);
+                        let _ = sender.send(StateChanges::BatteryChanged { level });
                     }
-                } else {
-                    while let Ok(size) = fh.read(buf.as_mut()) {
-                        let slice = &buf[0..size];
-                        let event = decode_event(slice);
-                        if debug_enabled {
-                            println!("Crown events: {:x?} {:?}", slice, event);
+                    let event = decode_event(slice);
+                    trace!("Crown events: {:x?} {:?}", slice, event);
+                    match event {
+                        CrownEvent::Connected => {
+                            let _ = sender.send(StateChanges::DeviceConnected { connected: true });
+                            switch_ratcher(&mut fh, ratchet.enabled);
+                            battery.probe(&mut fh);
                         }
-                        match event {
-                            CrownEvent::Connected => {
-                                switch_ratcher(&mut fh, ratchet_enabled);
-                            }
-                            CrownEvent::KeyPress { modifiers: m } => {
-                                let _ = sender.send(StateChanges::ModifiersChanged { modifiers: m });
-                                modifiers = m;
-                            }
-                            CrownEvent::Touch => {
-                                let _ = sender.send(StateChanges::CrownTouched { modifiers });
-                            }
-                            CrownEvent::Leave => {
-                                let _ = sender.send(StateChanges::CrownReleased { modifiers });
-                            }
-                            CrownEvent::Press => {
-                                had_rotation = false;
+                        CrownEvent::KeyPress { modifiers: m } => {
+                            let _ = sender.send(StateChanges::ModifiersChanged { modifiers: m });
+                            modifiers = m;
+                        }
+                        CrownEvent::Touch => {
+                            let _ = sender.send(StateChanges::CrownTouched { modifiers });
+                        }
+                        CrownEvent::Leave => {
+                            let _ = sender.send(StateChanges::CrownReleased { modifiers });
+                        }
+                        CrownEvent::Press => {
+                            had_rotation = false;
+                        }
+                        CrownEvent::Release if !had_rotation => {
+                            let _ = sender.send(StateChanges::CrownClicked { modifiers });
+                        }
+                        CrownEvent::Rotate { notch_amount, amount, pressed } => {
+                            if let Some(shift) = ratchet.smart_shift.as_mut() {
+                                if shift.rotated(amount) && ratchet.enabled {
+                                    debug!("Smart shift: free");
+                                    ratchet.enabled = false;
+                                    switch_ratcher(&mut fh, false);
+                                }
                             }
-                            CrownEvent::Release if !had_rotation => {
-                                let _ = sender.send(StateChanges::CrownClicked { modifiers });
+                            if (!ratchet.enabled && amount != 0) || notch_amount != 0 {
+                                had_rotation = true;
                             }
-                            CrownEvent::Rotate { notch_amount, amount, pressed } => {
-                                if (!ratchet_enabled && amount != 0) || notch_amount != 0 {
-                                    had_rotation = true;
-                                }
-                                if amount != 0 && (notch_amount != 0 || !ratchet_enabled) {
-                                    let _ = sender.send(StateChanges::CrownRotated { modifiers, amount, notch_amount, pressed });
-                                }
+                            if amount != 0 && (notch_amount != 0 || !ratchet.enabled) {
+                                let _ = sender.send(StateChanges::CrownRotated { modifiers, amount, notch_amount, pressed });
                             }
-                            _ => {}
                         }
+                        _ => {}
                     }
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATCHETED: u8 = 0x02;
+    const FREE: u8 = 0x01;
+
+    /// Returns ratchet modes written to the device.
+    fn written_modes(data: &[u8]) -> Vec<u8> {
+        data.chunks(20).map(|report| report[5]).collect()
+    }
+
+    #[test]
+    fn all_queued_commands_are_handled() {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        sender.send(CrownCommands::DisableRatchet).unwrap();
+        sender.send(CrownCommands::SmartShift { threshold: 30 }).unwrap();
+        sender.send(CrownCommands::EndStop).unwrap();
+        sender.send(CrownCommands::EndStop).unwrap();
+        let mut ratchet = Ratchet::new();
+        let mut written = Vec::new();
+        handle_commands(&mut ratchet, &receiver, &mut written);
+
+        assert_eq!(written_modes(&written), vec![FREE, RATCHETED, FREE]);
+        assert!(receiver.is_empty());
+        assert!(ratchet.enabled);
+        assert!(ratchet.smart_shift.is_some());
+        assert!(ratchet.end_stop_until.is_some());
+    }
+
+    #[test]
+    fn ratchet_mode_cancels_smart_shift() {
+        let mut ratchet = Ratchet::new();
+        let mut written = Vec::new();
+        ratchet.handle(CrownCommands::SmartShift { threshold: 30 }, &mut written);
+        assert!(ratchet.smart_shift.as_mut().unwrap().rotated(40));
+        ratchet.handle(CrownCommands::EnableRatchet, &mut written);
+        assert!(ratchet.smart_shift.is_none());
+        ratchet.handle(CrownCommands::DisableRatchet, &mut written);
+        ratchet.handle(CrownCommands::EndStop, &mut written);
+        assert_eq!(written_modes(&written), vec![RATCHETED, RATCHETED, FREE, RATCHETED]);
+    }
+
+    #[test]
+    fn restore_ratchets_crown_and_notifies() {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let (done, done_receiver) = crossbeam_channel::bounded(1);
+        sender.send(CrownCommands::SmartShift { threshold: 30 }).unwrap();
+        sender.send(CrownCommands::DisableRatchet).unwrap();
+        sender.send(CrownCommands::Restore { done }).unwrap();
+        let mut ratchet = Ratchet::new();
+        let mut written = Vec::new();
+        handle_commands(&mut ratchet, &receiver, &mut written);
+
+        assert_eq!(written_modes(&written), vec![RATCHETED, FREE, RATCHETED]);
+        assert!(done_receiver.try_recv().is_ok());
+        assert!(ratchet.enabled);
+        assert!(ratchet.smart_shift.is_none());
+    }
+
+    #[test]
+    fn discarded_restore_is_notified() {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let (done, done_receiver) = crossbeam_channel::bounded(1);
+        sender.send(CrownCommands::DisableRatchet).unwrap();
+        sender.send(CrownCommands::Restore { done }).unwrap();
+        discard_commands(&receiver);
+
+        assert!(receiver.is_empty());
+        assert!(done_receiver.try_recv().is_ok());
+    }
+
+    #[test]
+    fn battery_falls_back_to_battery_status() {
+        let mut battery = Battery::new();
+        let mut written = Vec::new();
+        battery.probe(&mut written);
+        assert_eq!(written, [0x10, DEVICE_INDEX, 0x00, SOFTWARE_ID, 0x10, 0x04, 0]);
+        // UNIFIED_BATTERY isn't supported, BATTERY_STATUS is at index 6
+        written.clear();
+        assert_eq!(battery.handle(&mut written, &[0x11, DEVICE_INDEX, 0x00, SOFTWARE_ID, 0, 0, 0]), None);
+        assert_eq!(written, [0x10, DEVICE_INDEX, 0x00, SOFTWARE_ID, 0x10, 0x00, 0]);
+        written.clear();
+        assert_eq!(battery.handle(&mut written, &[0x11, DEVICE_INDEX, 0x00, SOFTWARE_ID, 6, 0, 0]), None);
+        assert_eq!(written, [0x10, DEVICE_INDEX, 6, SOFTWARE_ID, 0, 0, 0]);
+        assert!(battery.refresh_at.is_some());
+
+        assert_eq!(battery.handle(&mut written, &[0x11, DEVICE_INDEX, 6, SOFTWARE_ID, 75, 0, 0]), Some(75));
+        assert_eq!(battery.handle(&mut written, &[0x11, DEVICE_INDEX, 6, 0x00, 70, 0, 0]), Some(70));
+        assert_eq!(battery.handle(&mut written, &[0x11, DEVICE_INDEX, 7, 0x00, 70, 0, 0]), None);
+    }
+}
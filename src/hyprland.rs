@@ -0,0 +1,180 @@
+use std::env;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+
+use crossbeam_channel::Sender;
+use serde::Deserialize;
+
+use crate::process::{command_line, program_path, sandbox_id};
+use crate::backend::FocusSource;
+use crate::supervisor::supervise;
+use crate::StateChanges;
+
+#[derive(Debug, Deserialize)]
+struct ActiveWindow {
+    #[serde(default)]
+    pid: i64,
+    #[serde(default)]
+    class: String,
+    #[serde(default)]
+    title: String,
+}
+
+/// Returns directory with sockets of the running Hyprland instance.
+fn socket_dir() -> Option<PathBuf> {
+    let signature = env::var_os("HYPRLAND_INSTANCE_SIGNATURE")?;
+    let runtime_dir = env::var_os("XDG_RUNTIME_DIR").
+        map(|dir| Path::new(&dir).join("hypr").join(&signature)).
+        filter(|dir| dir.exists());
+    Some(runtime_dir.unwrap_or_else(|| Path::new("/tmp/hypr").join(&signature)))
+}
+
+fn query_active_window(dir: &Path) -> io::Result<StateChanges> {
+    let mut stream = UnixStream::connect(dir.join(".socket.sock"))?;
+    stream.write_all(b"j/activewindow")?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+    parse_active_window(&reply)
+}
+
+fn parse_active_window(reply: &str) -> io::Result<StateChanges> {
+    let window = serde_json::from_str::<ActiveWindow>(reply).
+        map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let pid = if window.pid > 0 { window.pid as u32 } else { 0 };
+
+    Ok(StateChanges::FocusChanged {
+        pid,
+        program: if pid != 0 { program_path(pid) } else { "".to_owned() },
+        sandbox_id: sandbox_id(pid),
+        cmdline: command_line(pid),
+        class: window.class,
+        title: window.title,
+    })
+}
+
+/// Connects to event socket and reports currently focused window.
+fn connect(dir: &Path, sender: &Sender<StateChanges>) -> io::Result<UnixStream> {
+    let stream = UnixStream::connect(dir.join(".socket2.sock"))?;
+    // Hyprland returns invalid JSON when no window is focused
+    if let Ok(change) = query_active_window(dir) {
+        let _ = sender.send(change);
+    }
+    Ok(stream)
+}
+
+/// Tracks focused window through Hyprland event socket.
+pub(crate) struct HyprlandHandler {
+    dir: PathBuf,
+}
+
+impl HyprlandHandler {
+    /// Returns handler when Hyprland session is running.
+    pub fn detect() -> Option<HyprlandHandler> {
+        socket_dir().map(|dir| HyprlandHandler { dir })
+    }
+}
+
+impl FocusSource for HyprlandHandler {
+    fn name(&self) -> &'static str {
+        "hyprland"
+    }
+
+    fn spawn(&mut self, sender: Sender<StateChanges>) -> io::Result<()> {
+        let mut stream = Some(connect(&self.dir, &sender)?);
+        let dir = self.dir.clone();
+        let listener_sender = sender.clone();
+        let _x = supervise("hyprland", sender, move || {
+            let stream = match stream.take() {
+                Some(stream) => stream,
+                None => connect(&dir, &listener_sender)?,
+            };
+            hyprland_listener(stream, &dir, &listener_sender)
+        })?;
+        Ok(())
+    }
+}
+
+/// Returns `true` when event `line` reports focus change. Each focus change generates
+/// both `activewindow` and `activewindowv2`, older versions send only `activewindow`.
+fn is_focus_event(line: &str, has_v2_events: &mut bool) -> bool {
+    match line.split(">>").next().unwrap_or_default() {
+        "activewindowv2" => {
+            *has_v2_events = true;
+            true
+        }
+        "activewindow" => !*has_v2_events,
+        _ => false,
+    }
+}
+
+/// Reports focus changes until event socket is closed.
+fn hyprland_listener(stream: UnixStream, dir: &Path, sender: &Sender<StateChanges>) -> io::Result<()> {
+    let mut has_v2_events = false;
+
+    for line in BufReader::new(stream).lines() {
+        let line = line.map_err(|err| io::Error::new(err.kind(), format!("IPC connection lost: {}", err)))?;
+        if is_focus_event(&line, &mut has_v2_events) {
+            match query_active_window(dir) {
+                Ok(change) => {
+                    debug!("App switch: {:?}", change);
+                    let _ = sender.send(change);
+                }
+                Err(_) => {
+                    let _ = sender.send(StateChanges::FocusChanged {
+                        pid: 0,
+                        program: "".to_owned(),
+                        sandbox_id: None,
+                        cmdline: Vec::new(),
+                        class: "".to_owned(),
+                        title: "".to_owned(),
+                    });
+                }
+            }
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::UnexpectedEof, "IPC connection closed"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn active_window() {
+        let reply = r#"{
+            "address": "0x55d0c1f4e1a0",
+            "mapped": true,
+            "at": [10, 40],
+            "size": [1900, 1030],
+            "workspace": {"id": 1, "name": "1"},
+            "floating": false,
+            "class": "org.gnome.Nautilus",
+            "title": "Downloads",
+            "initialClass": "org.gnome.Nautilus",
+            "pid": -1,
+            "xwayland": false
+        }"#;
+        let change = parse_active_window(reply).unwrap();
+        assert!(matches!(change, StateChanges::FocusChanged { pid: 0, ref program, ref class, ref title, .. }
+                         if program.is_empty() && class == "org.gnome.Nautilus" && title == "Downloads"));
+    }
+
+    #[test]
+    fn no_active_window() {
+        // Reply when no window is focused
+        assert!(parse_active_window("Invalid").is_err());
+        let change = parse_active_window("{}").unwrap();
+        assert!(matches!(change, StateChanges::FocusChanged { pid: 0, ref class, .. } if class.is_empty()));
+    }
+
+    #[test]
+    fn focus_events() {
+        let mut has_v2_events = false;
+        assert!(is_focus_event("activewindow>>kitty,~", &mut has_v2_events));
+        assert!(!is_focus_event("workspace>>2", &mut has_v2_events));
+        assert!(is_focus_event("activewindowv2>>55d0c1f4e1a0", &mut has_v2_events));
+        assert!(!is_focus_event("activewindow>>kitty,~", &mut has_v2_events));
+        assert!(!is_focus_event("activewindowv3>>x", &mut has_v2_events));
+    }
+}
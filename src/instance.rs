@@ -0,0 +1,143 @@
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{self, BufRead, BufReader, Read, Seek, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use serde_json::json;
+
+use crate::control::{runtime_dir, socket_path};
+
+/// How long `--replace` waits for running instance to exit.
+const REPLACE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Per-user lock held while daemon is running, released when process exits.
+pub(crate) struct InstanceLock {
+    _file: File,
+}
+
+/// Lock file is kept in the same private directory as control socket.
+fn lock_path() -> io::Result<PathBuf> {
+    runtime_dir().map(|dir| dir.join("crown-controller.lock"))
+}
+
+/// Takes the lock without waiting, returns `false` when another process holds it.
+fn try_lock(file: &File) -> io::Result<bool> {
+    match file.try_lock() {
+        Ok(()) => Ok(true),
+        Err(TryLockError::WouldBlock) => Ok(false),
+        Err(TryLockError::Error(err)) => Err(err),
+    }
+}
+
+/// Returns pid written to lock file by the instance holding it.
+fn owner_pid(mut file: &File) -> String {
+    let mut pid = String::new();
+    let _ = file.rewind().and_then(|_| file.read_to_string(&mut pid));
+    Some(pid.trim().to_owned()).filter(|p| !p.is_empty()).unwrap_or_else(|| "unknown".to_owned())
+}
+
+/// Asks instance listening on control socket `path` to exit.
+fn request_shutdown(path: &Path) -> io::Result<()> {
+    let mut stream = UnixStream::connect(path)?;
+    stream.set_read_timeout(Some(REPLACE_TIMEOUT))?;
+    writeln!(stream, "{}", json!({ "command": "shutdown" }))?;
+    // Instance may exit before its reply is written
+    let _ = BufReader::new(stream).lines().next();
+    Ok(())
+}
+
+/// Locks `path`, when it's held by another instance and `replace` is set, `shutdown`
+/// is called and the lock is taken over after the instance exits.
+fn lock_file(path: &Path, replace: bool, shutdown: impl FnOnce() -> io::Result<()>) -> Result<InstanceLock, String> {
+    let mut file = OpenOptions::new().
+        read(true).
+        write(true).
+        create(true).
+        truncate(false).
+        mode(0o600).
+        custom_flags(libc::O_NOFOLLOW).
+        open(path).
+        map_err(|e| format!("Can't open lock file {}: {}", path.display(), e))?;
+    let error = |e: io::Error| format!("Can't lock {}: {}", path.display(), e);
+
+    if !try_lock(&file).map_err(error)? {
+        let pid = owner_pid(&file);
+        if !replace {
+            return Err(format!("Another instance is already running (pid {}), use --replace to take over", pid));
+        }
+        info!("Asking running instance (pid {}) to shut down", pid);
+        shutdown().map_err(|e| format!("Can't ask running instance (pid {}) to shut down: {}", pid, e))?;
+        let deadline = Instant::now() + REPLACE_TIMEOUT;
+        while !try_lock(&file).map_err(error)? {
+            if Instant::now() >= deadline {
+                return Err(format!("Running instance (pid {}) didn't shut down", pid));
+            }
+            sleep(Duration::from_millis(50));
+        }
+    }
+
+    let _ = file.set_len(0).and_then(|_| file.rewind()).and_then(|_| write!(file, "{}", std::process::id()));
+    Ok(InstanceLock {
+        _file: file,
+    })
+}
+
+/// Makes sure this is the only running daemon of current user, with `replace` running
+/// instance is asked to shut down and the lock is taken over after it exits.
+pub(crate) fn lock(replace: bool) -> Result<InstanceLock, String> {
+    let path = lock_path().map_err(|e| format!("Can't find lock file: {}", e))?;
+    lock_file(&path, replace, || request_shutdown(&socket_path()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::remove_file;
+    use std::os::unix::net::UnixListener;
+
+    use super::*;
+
+    #[test]
+    fn second_instance_fails_or_replaces_first() {
+        let path = std::env::temp_dir().join(format!("crown-controller-test-{}.lock", std::process::id()));
+        let _ = remove_file(&path);
+        // Locks of separately opened files conflict also within one process
+        let mut first = Some(lock_file(&path, false, || panic!("Lock is free")).unwrap());
+
+        let err = lock_file(&path, false, || panic!("Not replacing")).err().unwrap();
+        assert_eq!(err, format!("Another instance is already running (pid {}), use --replace to take over",
+                                std::process::id()));
+
+        let failed = lock_file(&path, true, || Err(io::Error::new(io::ErrorKind::ConnectionRefused, "refused")));
+        assert!(failed.err().unwrap().starts_with("Can't ask running instance"));
+
+        let second = lock_file(&path, true, || {
+            first.take();
+            Ok(())
+        });
+        assert!(second.is_ok());
+        assert!(first.is_none());
+        let _ = remove_file(&path);
+    }
+
+    #[test]
+    fn shutdown_waits_for_reply() {
+        let path = std::env::temp_dir().join(format!("crown-controller-test-{}.sock", std::process::id()));
+        let _ = remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let daemon = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut line = String::new();
+            let mut reader = BufReader::new(&stream);
+            reader.read_line(&mut line).unwrap();
+            writeln!(&stream, r#"{{"ok":true}}"#).unwrap();
+            line
+        });
+
+        request_shutdown(&path).unwrap();
+        assert_eq!(daemon.join().unwrap().trim_end(), r#"{"command":"shutdown"}"#);
+        let _ = remove_file(&path);
+    }
+}
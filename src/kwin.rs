@@ -0,0 +1,198 @@
+use std::env;
+use std::fs::{create_dir_all, write};
+use std::io;
+
+use crossbeam_channel::Sender;
+use directories::ProjectDirs;
+use zbus::blocking::{Connection, MessageIterator, Proxy};
+use zbus::message::Type;
+use zbus::{interface, MatchRule};
+
+use crate::backend::FocusSource;
+use crate::supervisor::supervise;
+use crate::StateChanges;
+
+const SCRIPT_NAME: &str = "crown-controller";
+const OBJECT_PATH: &str = "/org/prefiks/CrownController/KWin";
+const INTERFACE: &str = "org.prefiks.CrownController.KWin";
+
+/// KWin script reporting activated windows back to us, works with
+/// both KWin 5 (`clientActivated`) and KWin 6 (`windowActivated`) API.
+const SCRIPT: &str = r#"
+function crownNotify(window) {
+    if (!window) {
+        return;
+    }
+    callDBus("@SERVICE@", "@PATH@", "@INTERFACE@", "WindowActivated",
+             window.pid, String(window.resourceClass), String(window.caption));
+}
+if (workspace.windowActivated) {
+    workspace.windowActivated.connect(crownNotify);
+    crownNotify(workspace.activeWindow);
+} else {
+    workspace.clientActivated.connect(crownNotify);
+    crownNotify(workspace.activeClient);
+}
+"#;
+
+struct KWinFocus {
+    sender: Sender<StateChanges>,
+}
+
+#[interface(name = "org.prefiks.CrownController.KWin")]
+impl KWinFocus {
+    fn window_activated(&self, pid: i32, resource_class: String, caption: String) {
+        let pid = if pid > 0 { pid as u32 } else { 0 };
+        let change = StateChanges::FocusChanged {
+            pid,
+            program: if pid != 0 { crate::process::program_path(pid) } else { "".to_owned() },
+            sandbox_id: crate::process::sandbox_id(pid),
+            cmdline: crate::process::command_line(pid),
+            class: resource_class,
+            title: caption,
+        };
+        debug!("App switch: {:?}", change);
+        let _ = self.sender.send(change);
+    }
+}
+
+/// Returns the script calling `service` back.
+fn script(service: &str) -> String {
+    SCRIPT.
+        replace("@SERVICE@", service).
+        replace("@PATH@", OBJECT_PATH).
+        replace("@INTERFACE@", INTERFACE)
+}
+
+/// Returns `true` when running in Plasma Wayland session.
+fn is_plasma_wayland() -> bool {
+    env::var_os("WAYLAND_DISPLAY").is_some() &&
+        env::var("XDG_CURRENT_DESKTOP").is_ok_and(|v| v.split(':').any(|d| d == "KDE"))
+}
+
+/// Tracks focused window by loading KWin script that calls us over D-Bus.
+pub(crate) struct KWinHandler {
+    /// Session bus connection serving `KWinFocus`, set once script is loaded.
+    conn: Option<Connection>,
+}
+
+impl KWinHandler {
+    /// Returns handler when running in Plasma Wayland session.
+    pub fn detect() -> Option<KWinHandler> {
+        if is_plasma_wayland() {
+            Some(KWinHandler { conn: None })
+        } else {
+            None
+        }
+    }
+
+    /// Writes the script calling back `conn` and makes KWin run it.
+    fn load_script(conn: &Connection) -> zbus::Result<()> {
+        let unique_name = conn.unique_name().map(|n| n.to_string()).unwrap_or_default();
+        // KWin loads the script by path, data directory is private to the user unlike /tmp
+        let dir = ProjectDirs::from("org", "prefiks", "crown-controller").
+            map(|dirs| dirs.data_dir().to_path_buf()).
+            ok_or_else(|| zbus::Error::Failure("Can't find data directory".to_owned()))?;
+        let path = dir.join("kwin.js");
+        create_dir_all(&dir).
+            and_then(|_| write(&path, script(&unique_name))).
+            map_err(|e| zbus::Error::Failure(format!("Can't write {:?}: {}", path, e)))?;
+
+        let scripting = Proxy::new(conn, "org.kde.KWin", "/Scripting", "org.kde.kwin.Scripting")?;
+        let _: bool = scripting.call("unloadScript", &(SCRIPT_NAME, ))?;
+        let id: i32 = scripting.call("loadScript", &(path.to_string_lossy().as_ref(), SCRIPT_NAME))?;
+        if id < 0 {
+            return Err(zbus::Error::Failure("KWin refused to load script".to_owned()));
+        }
+        scripting.call_method("start", &())?;
+        Ok(())
+    }
+}
+
+/// Loads the script again each time KWin acquires its bus name, KWin doesn't keep
+/// scripts loaded over D-Bus when it restarts. Returns when the bus connection fails.
+fn reload_on_restart(conn: &Connection, load_now: bool) -> zbus::Result<()> {
+    let rule = MatchRule::builder().
+        msg_type(Type::Signal).
+        interface("org.freedesktop.DBus")?.
+        member("NameOwnerChanged")?.
+        arg(0, "org.kde.KWin")?.
+        build();
+    let iter = MessageIterator::for_match_rule(rule, conn, Some(16))?;
+    // Watch is set up before loading, so restart in between isn't missed
+    if load_now {
+        KWinHandler::load_script(conn)?;
+    }
+    for msg in iter {
+        let (_, _, new_owner): (String, String, String) = msg?.body().deserialize()?;
+        if !new_owner.is_empty() {
+            info!("KWin restarted, loading script again");
+            KWinHandler::load_script(conn)?;
+        }
+    }
+    Err(zbus::Error::Failure("Session bus connection closed".to_owned()))
+}
+
+impl FocusSource for KWinHandler {
+    fn name(&self) -> &'static str {
+        "kwin"
+    }
+
+    /// Loads the script, zbus serves its calls from its own thread.
+    fn spawn(&mut self, sender: Sender<StateChanges>) -> io::Result<()> {
+        let conn = Connection::session().map_err(io::Error::other)?;
+        conn.object_server().at(OBJECT_PATH, KWinFocus { sender: sender.clone() }).map_err(io::Error::other)?;
+        KWinHandler::load_script(&conn).map_err(io::Error::other)?;
+
+        let watch_conn = conn.clone();
+        // The script was just loaded, after a failure it's loaded again
+        let mut load_now = false;
+        let _x = supervise("kwin", sender, move || {
+            let res = reload_on_restart(&watch_conn, load_now).map_err(io::Error::other);
+            load_now = true;
+            res
+        })?;
+        self.conn = Some(conn);
+        Ok(())
+    }
+}
+
+impl Drop for KWinHandler {
+    fn drop(&mut self) {
+        let conn = match self.conn {
+            Some(ref conn) => conn,
+            None => return,
+        };
+        if let Ok(scripting) = Proxy::new(conn, "org.kde.KWin", "/Scripting", "org.kde.kwin.Scripting") {
+            let _: zbus::Result<bool> = scripting.call("unloadScript", &(SCRIPT_NAME, ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn script_calls_back_service() {
+        let script = script(":1.42");
+        assert!(script.contains(
+            r#"callDBus(":1.42", "/org/prefiks/CrownController/KWin", "org.prefiks.CrownController.KWin", "#));
+        assert!(!script.contains('@'));
+    }
+
+    #[test]
+    fn activated_window() {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let focus = KWinFocus { sender };
+        // Windows of Wayland clients without known pid report -1
+        focus.window_activated(-1, "org.kde.dolphin".to_owned(), "Home — Dolphin".to_owned());
+        let change = receiver.try_recv().unwrap();
+        assert!(matches!(change, StateChanges::FocusChanged { pid: 0, ref program, ref class, ref title, .. }
+                         if program.is_empty() && class == "org.kde.dolphin" && title == "Home — Dolphin"));
+
+        focus.window_activated(std::process::id() as i32, "test".to_owned(), String::new());
+        let change = receiver.try_recv().unwrap();
+        assert!(matches!(change, StateChanges::FocusChanged { ref program, .. } if !program.is_empty()));
+    }
+}
@@ -0,0 +1,181 @@
+use std::collections::HashMap;
+use std::env;
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::{OnceLock, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use serde_json::json;
+
+/// Format of log lines written to stderr.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) enum Format {
+    Text,
+    Json,
+    /// Text prefixed with syslog priority (`<3>`), understood by journald.
+    Journal,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            "journal" => Ok(Format::Journal),
+            _ => Err(format!("Unknown log format {}, expected text, json or journal", s)),
+        }
+    }
+}
+
+impl Format {
+    /// Journal format when stderr is connected to journald, text otherwise.
+    pub(crate) fn detect() -> Format {
+        if env::var_os("JOURNAL_STREAM").is_some() { Format::Journal } else { Format::Text }
+    }
+}
+
+/// Levels of subsystems parsed from spec like `info,hid=trace,config=debug`.
+struct Filter {
+    default: LevelFilter,
+    subsystems: HashMap<String, LevelFilter>,
+    spec: String,
+}
+
+impl Filter {
+    fn parse(spec: &str) -> Result<Filter, String> {
+        let mut filter = Filter {
+            default: LevelFilter::Info,
+            subsystems: HashMap::new(),
+            spec: spec.to_owned(),
+        };
+        for part in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let level = |level: &str| LevelFilter::from_str(level).map_err(|_| format!("Unknown log level {}", level));
+            match part.split_once('=') {
+                Some((subsystem, l)) => {
+                    filter.subsystems.insert(subsystem.to_owned(), level(l)?);
+                }
+                None => filter.default = level(part)?,
+            }
+        }
+        Ok(filter)
+    }
+
+    fn level(&self, subsystem: &str) -> LevelFilter {
+        self.subsystems.get(subsystem).copied().unwrap_or(self.default)
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.subsystems.values().copied().chain(Some(self.default)).max().unwrap_or(LevelFilter::Info)
+    }
+}
+
+/// Returns subsystem of log target, module name for `crown_controller::hid` or explicit target like `exec`.
+fn subsystem(target: &str) -> &str {
+    match target.strip_prefix("crown_controller") {
+        Some("") => "main",
+        Some(module) => module.trim_start_matches("::").split("::").next().unwrap_or(module),
+        None => target,
+    }
+}
+
+fn priority(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+struct Logger {
+    filter: RwLock<Filter>,
+    format: Format,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.filter.read().map_or(true, |f| metadata.level() <= f.level(subsystem(metadata.target())))
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let subsystem = subsystem(record.target());
+        let line = match self.format {
+            Format::Text => format!("{:<5} [{}] {}", record.level(), subsystem, record.args()),
+            Format::Journal => format!("<{}>[{}] {}", priority(record.level()), subsystem, record.args()),
+            Format::Json => json!({
+                "time": SystemTime::now().duration_since(UNIX_EPOCH).map_or(0.0, |d| d.as_secs_f64()),
+                "level": record.level().to_string().to_lowercase(),
+                "subsystem": subsystem,
+                "message": record.args().to_string(),
+            }).to_string(),
+        };
+        let _ = writeln!(std::io::stderr().lock(), "{}", line);
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+/// Installs logger writing to stderr with levels given by `spec`.
+pub(crate) fn init(spec: &str, format: Format) -> Result<(), String> {
+    let filter = Filter::parse(spec)?;
+    log::set_max_level(filter.max_level());
+    let logger = LOGGER.get_or_init(|| Logger { filter: RwLock::new(filter), format });
+    log::set_logger(logger).map_err(|e| e.to_string())
+}
+
+/// Replaces levels of subsystems while running.
+pub(crate) fn set_filter(spec: &str) -> Result<(), String> {
+    let filter = Filter::parse(spec)?;
+    let logger = LOGGER.get().ok_or("Logger isn't initialized")?;
+    log::set_max_level(filter.max_level());
+    if let Ok(mut current) = logger.filter.write() {
+        *current = filter;
+    }
+    Ok(())
+}
+
+/// Returns spec of current levels.
+pub(crate) fn filter_spec() -> String {
+    LOGGER.get().and_then(|l| l.filter.read().ok().map(|f| f.spec.clone())).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filter_with_subsystem_levels() {
+        let filter = Filter::parse("info, hid=trace,config=debug").unwrap();
+        assert_eq!(filter.level("hid"), LevelFilter::Trace);
+        assert_eq!(filter.level("config"), LevelFilter::Debug);
+        assert_eq!(filter.level("x11"), LevelFilter::Info);
+        assert_eq!(filter.max_level(), LevelFilter::Trace);
+
+        let filter = Filter::parse("warn").unwrap();
+        assert_eq!(filter.level("hid"), LevelFilter::Warn);
+        assert_eq!(filter.max_level(), LevelFilter::Warn);
+        assert_eq!(Filter::parse("").unwrap().level("hid"), LevelFilter::Info);
+    }
+
+    #[test]
+    fn invalid_levels_are_rejected() {
+        assert_eq!(Filter::parse("loud").err(), Some("Unknown log level loud".to_owned()));
+        assert_eq!(Filter::parse("info,hid=").err(), Some("Unknown log level ".to_owned()));
+    }
+
+    #[test]
+    fn subsystem_of_targets() {
+        assert_eq!(subsystem("crown_controller::hid"), "hid");
+        assert_eq!(subsystem("crown_controller::config::tests"), "config");
+        assert_eq!(subsystem("crown_controller"), "main");
+        assert_eq!(subsystem("exec"), "exec");
+    }
+}
@@ -1,91 +1,561 @@
-use crate::config::{ConfigFile, Modifier, Operation, RatchetMode, Action};
-use crate::hid::HidHandler;
-use crate::x11::X11Handler;
+#[macro_use]
+extern crate log;
+
+use crate::backend::Backends;
+use crate::config::{key_name, ConfigFile, Modifier, Operation, RatchetMode, Action};
+use crate::control::{ControlHandler, Reply, Request, Status, Subscribers};
+use crate::logging::Format;
+#[cfg(feature = "mpris")]
+use crate::mpris::MprisHandler;
+use crate::plugin::{PluginHandler, PluginMessage};
+use crate::process::{command_line, program_path, TerminalTracker};
+#[cfg(feature = "scripting")]
+use crate::script::{ScriptEngine, ScriptEvent};
+use crate::supervisor::Health;
+use crate::throttle::{BatchQueue, Cooldowns};
+use crossbeam_channel::{RecvTimeoutError, Sender};
+use serde::Serialize;
+use std::collections::BTreeMap;
 use std::process::Command;
+use std::time::{Duration, Instant};
+
+/// Script operations queued by scripts can call other scripts only this many levels deep.
+#[cfg(feature = "scripting")]
+const MAX_SCRIPT_DEPTH: usize = 8;
 
+mod backend;
+#[cfg(feature = "x11")]
 mod x11;
 mod hid;
+mod instance;
 mod config;
+mod control;
+mod explain;
+mod logging;
+#[cfg(feature = "dbus")]
+mod dbus;
 mod udev;
+mod throttle;
+#[cfg(feature = "mpris")]
+mod mpris;
+mod plugin;
+mod process;
+#[cfg(feature = "scripting")]
+mod script;
+mod supervisor;
+#[cfg(feature = "sway")]
+mod sway;
+mod systemd;
+#[cfg(feature = "hyprland")]
+mod hyprland;
+#[cfg(feature = "wayland")]
+mod wayland;
+#[cfg(feature = "kde")]
+mod kwin;
 
 pub(crate) mod keysyms {
     include!(concat!(env!("OUT_DIR"), "/keysyms.rs"));
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub(crate) enum StateChanges {
-    FocusChanged { pid: u32, program: String },
+    /// Only sent by display and compositor backends.
+    #[cfg_attr(not(any(feature = "x11", feature = "wayland", feature = "sway", feature = "hyprland", feature = "kde")),
+               allow(dead_code))]
+    FocusChanged {
+        pid: u32,
+        program: String,
+        /// Flatpak application id or Snap name.
+        sandbox_id: Option<String>,
+        cmdline: Vec<String>,
+        class: String,
+        title: String,
+    },
+    DeviceConnected { connected: bool },
+    /// Battery level in percent.
+    BatteryChanged { level: u8 },
+    /// Supervised listener thread of `backend` stopped or was restarted.
+    BackendHealth { backend: &'static str, health: Health },
     ModifiersChanged { modifiers: u8 },
     CrownTouched { modifiers: u8 },
     CrownReleased { modifiers: u8 },
     CrownClicked { modifiers: u8 },
     CrownRotated { modifiers: u8, amount: i16, notch_amount: i16, pressed: bool },
+    /// Request from control socket, reply lines are sent through `reply`.
+    #[serde(skip)]
+    Control { request: Request, reply: Sender<String> },
+    /// Message from plugin with index `plugin`.
+    #[serde(skip)]
+    Plugin { plugin: usize, message: PluginMessage },
+}
+
+impl StateChanges {
+    pub(crate) fn is_crown_event(&self) -> bool {
+        matches!(self, StateChanges::CrownTouched { .. } | StateChanges::CrownReleased { .. } |
+            StateChanges::CrownClicked { .. } | StateChanges::CrownRotated { .. })
+    }
 }
 
-fn execute_commands(commands: &[Operation], x11_handler: &X11Handler, debug_enabled: bool) {
-    for command in commands {
-        if debug_enabled {
-            println!("Exec {:?}", command);
+fn spawn_command(command: &str) {
+    let mut parts = command.split_ascii_whitespace();
+    if let Some(cmd) = parts.next() {
+        let _ = Command::new(cmd).args(parts).spawn();
+    }
+}
+
+struct Executor {
+    backends: Backends,
+    ratchet_mode: RatchetMode,
+    #[cfg(feature = "mpris")]
+    mpris_handler: MprisHandler,
+    batches: BatchQueue,
+    cooldowns: Cooldowns,
+    subscribers: Subscribers,
+    #[cfg(feature = "scripting")]
+    scripts: ScriptEngine,
+    /// Title of focused window, passed to scripts.
+    title: String,
+    /// Action, layer and notch amount of crown event being handled.
+    event: (Action, Modifier, i16),
+    /// Number of `Script` operations being executed, they can queue more of them.
+    #[cfg(feature = "scripting")]
+    script_depth: usize,
+    /// Operations with effects outside of the daemon are logged instead of executed.
+    dry_run: bool,
+}
+
+impl Executor {
+    fn set_ratchet_mode(&mut self, config: &mut ConfigFile, mode: RatchetMode) {
+        if mode != self.ratchet_mode {
+            self.ratchet_mode = mode;
+            if self.dry_run {
+                info!(target: "exec", "Dry run: ratchet mode {:?}", mode);
+            } else {
+                self.backends.input.set_ratchet_mode(mode, config.smart_shift_threshold());
+            }
         }
+    }
+
+    /// Logs what `command` would do in dry run, returns `false` for operations that only
+    /// change daemon state, these are executed as usual.
+    fn log_dry_run(&self, command: &Operation, delta: i16) -> bool {
         match command {
-            Operation::KeyPress(keysym, modifiers) => {
-                x11_handler.send_key(*keysym, *modifiers);
+            Operation::KeyPress(keysym, modifiers) =>
+                info!(target: "exec", "Dry run: key {} (keysym {:#x}, modifiers {:#x})", key_name(*keysym, *modifiers), keysym, modifiers),
+            Operation::Execute(command) =>
+                info!(target: "exec", "Dry run: execute {:?}", command.split_ascii_whitespace().collect::<Vec<_>>()),
+            Operation::Mpris(operation) => info!(target: "exec", "Dry run: MPRIS {:?} by {}", operation, delta),
+            Operation::Scroll(amount) => info!(target: "exec", "Dry run: scroll {}", amount * delta as i32),
+            _ => return false,
+        }
+        true
+    }
+
+    /// Executes `commands` defined in `profile`, `key` identifies position of
+    /// commands in the mapping for keeping state of `Toggle` and `Cycle`.
+    fn execute_commands(&mut self, config: &mut ConfigFile, profile: &str, key: &str, commands: &[Operation],
+                        delta: i16) {
+        for (idx, command) in commands.iter().enumerate() {
+            debug!(target: "exec", "Exec {:?}", command);
+            self.subscribers.broadcast(Reply::Operation(command));
+            if self.dry_run && self.log_dry_run(command, delta) {
+                continue;
             }
-            Operation::Execute(command) => {
-                let mut parts = command.split_ascii_whitespace();
-                if let Some(cmd) = parts.next() {
-                    let _ = Command::new(cmd).args(parts).spawn();
+            match command {
+                Operation::KeyPress(keysym, modifiers) => {
+                    self.backends.sink.send_key(*keysym, *modifiers);
+                }
+                Operation::Execute(command) => {
+                    spawn_command(command);
+                }
+                Operation::Batch { command, idle } => {
+                    self.batches.add(command, Duration::from_millis(*idle), delta);
+                }
+                #[cfg(feature = "mpris")]
+                Operation::Mpris(operation) => {
+                    self.mpris_handler.execute(operation, delta);
+                }
+                #[cfg(not(feature = "mpris"))]
+                Operation::Mpris(operation) => {
+                    warn!(target: "exec", "Ignoring MPRIS {:?}, MPRIS support is disabled", operation);
+                }
+                Operation::Adjust(name) => {
+                    self.adjust_value(config, name, delta);
+                }
+                Operation::Toggle { on, off } => {
+                    let key = format!("{}/{}", key, idx);
+                    let commands = if config.next_position(profile, &key, 2) == 0 { on } else { off };
+                    self.execute_commands(config, profile, &key, commands, delta);
+                }
+                Operation::Cycle(steps) if !steps.is_empty() => {
+                    let key = format!("{}/{}", key, idx);
+                    let position = config.next_position(profile, &key, steps.len());
+                    self.execute_commands(config, profile, &key, &steps[position], delta);
+                }
+                Operation::Cycle(_) => {}
+                Operation::SetRatchet(mode) => {
+                    self.set_ratchet_mode(config, *mode);
+                }
+                Operation::Scroll(amount) => {
+                    self.backends.sink.scroll(amount * delta as i32);
+                }
+                Operation::ToggleRatchet => {
+                    let mode = match self.ratchet_mode {
+                        RatchetMode::Ratcheted => RatchetMode::Free,
+                        _ => RatchetMode::Ratcheted,
+                    };
+                    self.set_ratchet_mode(config, mode);
+                }
+                #[cfg(feature = "scripting")]
+                Operation::Script(function) if self.script_depth >= MAX_SCRIPT_DEPTH => {
+                    warn!(target: "exec", "Not calling script function {}, scripts are nested too deep", function);
+                }
+                #[cfg(feature = "scripting")]
+                Operation::Script(function) => {
+                    let (action, modifiers, notch) = self.event;
+                    let event = ScriptEvent {
+                        action,
+                        amount: delta,
+                        notch,
+                        modifiers,
+                        ratchet_mode: self.ratchet_mode,
+                        app: config.active_app().unwrap_or_default(),
+                        class: config.active_class().unwrap_or_default(),
+                        title: &self.title,
+                        profile: config.active_profile().unwrap_or_default(),
+                    };
+                    let operations = self.scripts.call(function, &event);
+                    let key = format!("{}/{}", key, idx);
+                    self.script_depth += 1;
+                    for (operation, delta) in operations {
+                        self.execute_commands(config, profile, &key, std::slice::from_ref(&operation), delta);
+                    }
+                    self.script_depth -= 1;
+                }
+                #[cfg(not(feature = "scripting"))]
+                Operation::Script(function) => {
+                    warn!(target: "exec", "Ignoring script function {}, scripting support is disabled", function);
                 }
             }
         }
     }
+
+    fn adjust_value(&mut self, config: &mut ConfigFile, name: &str, delta: i16) {
+        if let Some(change) = config.adjust_value(name, delta) {
+            debug!(target: "exec", "Value {} = {}", name, change.value);
+            if change.at_bound && self.dry_run {
+                info!(target: "exec", "Dry run: end stop feedback");
+            } else if change.at_bound {
+                self.backends.input.end_stop();
+            }
+            if change.changed {
+                let commands: Vec<_> = change.definition.on_change.iter().map(|op| match op {
+                    Operation::Execute(command) => Operation::Execute(command.replace("{value}", &change.value.to_string())),
+                    Operation::Batch { command, idle } => Operation::Batch {
+                        command: command.replace("{value}", &change.value.to_string()),
+                        idle: *idle,
+                    },
+                    _ => op.clone(),
+                }).collect();
+                self.execute_commands(config, &change.profile, &format!("value/{}", name), &commands, delta);
+            }
+        } else {
+            warn!(target: "exec", "Unknown value {}", name);
+        }
+    }
+
+    fn run_action(&mut self, config: &mut ConfigFile, modifiers: Modifier, action: Action, delta: i16, notch: i16) {
+        self.event = (action, modifiers, notch);
+        if let Some((profile, mapping)) = config.get_mapping_for_modifiers(modifiers, action) {
+            let cooldown = mapping.cooldown.get(&action).map(|v| Duration::from_millis(*v));
+            let actions = ConfigFile::get_actions_from_mapping(&mapping, action).unwrap_or_default();
+            let ready = match cooldown {
+                Some(cooldown) => self.cooldowns.try_run(&profile, modifiers, action, cooldown),
+                None => true,
+            };
+            if ready {
+                let key = format!("{:?}/{:?}", modifiers, action);
+                self.execute_commands(config, &profile, &key, actions, delta);
+            } else {
+                debug!(target: "exec", "Skipping {:?}, cooldown active", action);
+            }
+        }
+    }
+
+    fn run_expired_batches(&mut self) {
+        for command in self.batches.take_expired() {
+            if self.dry_run {
+                info!(target: "exec", "Dry run: execute batch {:?}", command.split_ascii_whitespace().collect::<Vec<_>>());
+                continue;
+            }
+            debug!(target: "exec", "Exec batch {}", command);
+            spawn_command(&command);
+        }
+    }
+}
+
+/// Daemon state that isn't kept by configuration or executor.
+struct DaemonState {
+    connected: bool,
+    health: BTreeMap<String, Health>,
+    battery: Option<u8>,
+    modifiers: Modifier,
+    forced_layer: Option<Modifier>,
 }
 
-fn main() -> () {
+impl DaemonState {
+    /// Layer whose mapping is used for crown actions.
+    fn layer(&self) -> Modifier {
+        self.forced_layer.unwrap_or(self.modifiers)
+    }
+
+    /// Device is connected and display and focus backends are running.
+    fn is_ready(&self) -> bool {
+        self.connected && self.health.values().all(|health| health.running)
+    }
+
+    fn status(&self, config: &ConfigFile, ratchet_mode: RatchetMode) -> Status {
+        Status {
+            connected: self.connected,
+            health: self.health.clone(),
+            battery: self.battery,
+            app: config.active_app().map(|v| v.to_owned()),
+            class: config.active_class().map(|v| v.to_owned()),
+            profile: config.active_profile().map(|v| v.to_owned()),
+            forced_profile: config.forced_profile().map(|v| v.to_owned()),
+            ratchet_mode,
+            modifiers: self.modifiers,
+            layer: self.layer(),
+            forced_layer: self.forced_layer,
+            log_filter: logging::filter_spec(),
+        }
+    }
+}
+
+fn select_foreground(config: &mut ConfigFile, pid: Option<u32>) {
+    let program = pid.map(program_path).unwrap_or_default();
+    debug!("Terminal foreground process: {:?} {}", pid, program);
+    config.select_foreground(&program, &pid.map(command_line).unwrap_or_default());
+}
+
+fn main() {
     let mut args = pico_args::Arguments::from_env();
 
+    if let Ok(Some(command)) = args.subcommand() {
+        match command.as_str() {
+            "ctl" => std::process::exit(control::run_client(args)),
+            "explain" => {
+                let _ = logging::init("warn", Format::Text);
+                std::process::exit(explain::run(args))
+            }
+            "install-service" => std::process::exit(systemd::install_service(&std::env::args().skip(2).collect::<Vec<_>>())),
+            _ => {
+                println!("Unknown command {}", command);
+                std::process::exit(2);
+            }
+        }
+    }
     let debug_enabled: bool = args.contains(["-d", "--debug"]);
+    let log_spec: String = args.opt_value_from_str("--log").unwrap_or(None).
+        unwrap_or_else(|| if debug_enabled { "debug" } else { "info" }.to_owned());
+    let log_format: Format = match args.opt_value_from_str("--log-format") {
+        Ok(format) => format.unwrap_or_else(Format::detect),
+        Err(err) => {
+            println!("{}", err);
+            std::process::exit(2);
+        }
+    };
+    if let Err(err) = logging::init(&log_spec, log_format) {
+        println!("Can't set up logging: {}", err);
+        std::process::exit(2);
+    }
+    let plugin_commands: Vec<String> = args.values_from_str("--plugin").unwrap_or_default();
+    let _instance = match instance::lock(args.contains("--replace")) {
+        Ok(lock) => lock,
+        Err(err) => {
+            error!("{}", err);
+            std::process::exit(1);
+        }
+    };
 
+    let mut notifier = systemd::Notifier::from_env();
     let (sender, receiver) = crossbeam_channel::unbounded();
-    let x11_handler = X11Handler::new(sender.clone(), debug_enabled).unwrap();
-    let hid_handler = HidHandler::new(sender.clone(), debug_enabled).unwrap();
+    let backends = match backend::detect(&sender) {
+        Ok(backends) => backends,
+        Err(err) => {
+            error!("Can't start crown listener: {}", err);
+            std::process::exit(1);
+        }
+    };
+    info!("Using {} backend for focus tracking and {} for sending keys",
+          backends.focus.as_ref().map_or(backends.sink.name(), |f| f.name()), backends.sink.name());
+    let mut executor = Executor {
+        backends,
+        ratchet_mode: RatchetMode::Ratcheted,
+        #[cfg(feature = "mpris")]
+        mpris_handler: MprisHandler::new(),
+        batches: BatchQueue::new(),
+        cooldowns: Cooldowns::new(),
+        subscribers: Subscribers::new(),
+        #[cfg(feature = "scripting")]
+        scripts: ScriptEngine::new(),
+        title: String::new(),
+        event: (Action::Touch, Modifier::None, 0),
+        #[cfg(feature = "scripting")]
+        script_depth: 0,
+        dry_run: args.contains("--dry-run"),
+    };
+    if executor.dry_run {
+        info!("Dry run, keys, scrolling, commands, MPRIS requests and device changes are only logged");
+    }
+    let _control_handler = ControlHandler::new(sender.clone()).
+        map_err(|err| error!("Can't create control socket: {}", err)).
+        ok();
     let mut config = ConfigFile::new();
-    let mut last_mode = RatchetMode::Ratcheted;
-    let mut last_modifiers = Modifier::None;
+    let mut state = DaemonState {
+        connected: false,
+        health: BTreeMap::new(),
+        battery: None,
+        modifiers: Modifier::None,
+        forced_layer: None,
+    };
+    #[cfg(feature = "dbus")]
+    let mut dbus_handler = dbus::DbusHandler::new(sender.clone()).
+        map_err(|err| warn!("Can't register on session bus: {}", err)).
+        ok();
+    let mut terminal = TerminalTracker::new();
+    let plugin_commands: Vec<String> = config.plugins().into_iter().chain(plugin_commands).collect();
+    let mut plugins = PluginHandler::new(&plugin_commands, &sender);
 
     loop {
-        let res = receiver.recv().unwrap();
-        if debug_enabled {
-            println!("Processing {:?}", res);
+        // Deadlines are checked after every message too, so busy channel doesn't delay them
+        executor.run_expired_batches();
+        if let Some(foreground) = terminal.poll() {
+            select_foreground(&mut config, foreground);
+            let mode = config.ratchet_mode_for_modifier(state.layer());
+            executor.set_ratchet_mode(&mut config, mode);
+        }
+        notifier.watchdog();
+        let status = state.status(&config, executor.ratchet_mode);
+        notifier.status(systemd::status_line(&status));
+        #[cfg(feature = "dbus")]
+        if let Some(handler) = dbus_handler.as_mut() {
+            handler.update(status);
+        }
+        let deadline = executor.batches.next_deadline().into_iter().
+            chain(terminal.next_poll()).
+            chain(notifier.next_watchdog()).
+            min();
+        let res = if let Some(deadline) = deadline {
+            match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(res) => res,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => panic!("State channel disconnected"),
+            }
+        } else {
+            receiver.recv().unwrap()
+        };
+        debug!("Processing {:?}", res);
+        if !matches!(res, StateChanges::Control { .. } | StateChanges::Plugin { .. }) {
+            executor.subscribers.broadcast(Reply::Event(&res));
+        }
+        plugins.send(&res);
+        #[cfg(feature = "dbus")]
+        if let Some(handler) = dbus_handler.as_ref() {
+            handler.crown_event(&res, state.forced_layer);
+        }
+        if res.is_crown_event() && plugins.claimed() {
+            continue;
         }
         match res {
-            StateChanges::FocusChanged { program, .. } => {
-                config.select_app(&program);
-                let mode = config.ratchet_mode_for_modifier(last_modifiers);
-                if mode != last_mode {
-                    last_mode = mode;
-                    match mode {
-                        RatchetMode::Ratcheted => hid_handler.enable_ratcher(),
-                        _ => hid_handler.disable_ratcher(),
-                    };
+            StateChanges::FocusChanged { pid, program, sandbox_id, cmdline, class, title } => {
+                executor.title = title;
+                config.select_app(&program, sandbox_id.as_deref(), &cmdline, &class);
+                if let Some(foreground) = terminal.focus(pid) {
+                    select_foreground(&mut config, Some(foreground));
+                }
+                let mode = config.ratchet_mode_for_modifier(state.layer());
+                executor.set_ratchet_mode(&mut config, mode);
+            }
+            StateChanges::DeviceConnected { connected } => {
+                if connected && !state.connected && !executor.dry_run {
+                    // Restarted listener opens device with default ratchet mode
+                    executor.backends.input.set_ratchet_mode(executor.ratchet_mode, config.smart_shift_threshold());
+                }
+                state.connected = connected;
+                if state.is_ready() {
+                    notifier.ready();
+                }
+            }
+            StateChanges::BackendHealth { backend, health } => {
+                if backend == "hid" && !health.running {
+                    state.connected = false;
+                }
+                state.health.insert(backend.to_owned(), health);
+                if state.is_ready() {
+                    notifier.ready();
+                }
+            }
+            StateChanges::BatteryChanged { level } => {
+                state.battery = Some(level);
+            }
+            StateChanges::Control { request: Request::Shutdown, reply } => {
+                info!("Shutting down");
+                let _ = reply.send(Reply::Ok(true).to_line());
+                if !executor.dry_run {
+                    executor.backends.input.restore();
+                }
+                break;
+            }
+            StateChanges::Control { request, reply } => {
+                let line = match request {
+                    Request::Status => Reply::Status(state.status(&config, executor.ratchet_mode)),
+                    Request::ForceProfile { profile } => {
+                        if config.force_profile(profile) {
+                            let mode = config.ratchet_mode_for_modifier(state.layer());
+                            executor.set_ratchet_mode(&mut config, mode);
+                            Reply::Ok(true)
+                        } else {
+                            Reply::Error("Unknown profile".to_owned())
+                        }
+                    }
+                    Request::ForceLayer { layer } => {
+                        state.forced_layer = layer;
+                        let mode = config.ratchet_mode_for_modifier(state.layer());
+                        executor.set_ratchet_mode(&mut config, mode);
+                        Reply::Ok(true)
+                    }
+                    Request::SetRatchet { mode } => {
+                        executor.set_ratchet_mode(&mut config, mode);
+                        Reply::Ok(true)
+                    }
+                    Request::SetLogLevel { filter } => match logging::set_filter(&filter) {
+                        Ok(()) => Reply::Ok(true),
+                        Err(err) => Reply::Error(err),
+                    },
+                    Request::Subscribe => {
+                        executor.subscribers.add(reply.clone());
+                        Reply::Ok(true)
+                    }
+                    Request::Inject { .. } | Request::Shutdown => Reply::Error("Unexpected request".to_owned()),
+                }.to_line();
+                let _ = reply.send(line);
+            }
+            StateChanges::Plugin { plugin, message } => {
+                if let Some(operations) = plugins.handle(plugin, message) {
+                    executor.execute_commands(&mut config, "plugin", &format!("plugin/{}", plugin), &operations, 1);
                 }
             }
             StateChanges::ModifiersChanged { modifiers } => {
                 let modifiers = Modifier::from(modifiers);
-                if last_modifiers != modifiers {
-                    last_modifiers = modifiers;
-                    let mode = config.ratchet_mode_for_modifier(modifiers);
-                    if mode != last_mode {
-                        last_mode = mode;
-                        match mode {
-                            RatchetMode::Ratcheted => hid_handler.enable_ratcher(),
-                            _ => hid_handler.disable_ratcher(),
-                        };
-                    }
+                if state.modifiers != modifiers {
+                    state.modifiers = modifiers;
+                    let mode = config.ratchet_mode_for_modifier(state.layer());
+                    executor.set_ratchet_mode(&mut config, mode);
                 }
             }
             StateChanges::CrownRotated { modifiers, amount, pressed, notch_amount, .. } => {
-                let modifiers = Modifier::from(modifiers);
+                let modifiers = state.forced_layer.unwrap_or(Modifier::from(modifiers));
                 let action = match (amount, pressed) {
                     (amount, true) if amount > 0 => Action::RightPressed,
                     (amount, true) if amount < 0 => Action::LeftPressed,
@@ -93,31 +563,185 @@ fn main() -> () {
                     (amount, _) if amount < 0 => Action::Left,
                     _ => continue
                 };
-                if last_mode == RatchetMode::Ratcheted && notch_amount == 0 {
+                if executor.ratchet_mode == RatchetMode::Ratcheted && notch_amount == 0 {
                     continue;
                 }
-                if let Some(actions) = config.get_actions_for_modifiers(modifiers, action) {
-                    execute_commands(actions, &x11_handler, debug_enabled);
-                }
+                let delta = if executor.ratchet_mode == RatchetMode::Free || notch_amount == 0 {
+                    amount
+                } else {
+                    notch_amount
+                };
+                executor.run_action(&mut config, modifiers, action, delta, notch_amount);
             }
-            StateChanges::CrownTouched {modifiers} => {
-                let modifiers = Modifier::from(modifiers);
-                if let Some(actions) = config.get_actions_for_modifiers(modifiers, Action::Touch) {
-                    execute_commands(actions, &x11_handler, debug_enabled);
-                }
+            StateChanges::CrownTouched { modifiers } => {
+                executor.run_action(&mut config, state.forced_layer.unwrap_or(Modifier::from(modifiers)), Action::Touch, 1, 0);
             }
-            StateChanges::CrownReleased {modifiers} => {
-                let modifiers = Modifier::from(modifiers);
-                if let Some(actions) = config.get_actions_for_modifiers(modifiers, Action::Release) {
-                    execute_commands(actions, &x11_handler, debug_enabled);
-                }
+            StateChanges::CrownReleased { modifiers } => {
+                executor.run_action(&mut config, state.forced_layer.unwrap_or(Modifier::from(modifiers)), Action::Release, 1, 0);
             }
             StateChanges::CrownClicked { modifiers } => {
-                let modifiers = Modifier::from(modifiers);
-                if let Some(actions) = config.get_actions_for_modifiers(modifiers, Action::Click) {
-                    execute_commands(actions, &x11_handler, debug_enabled);
-                }
+                executor.run_action(&mut config, state.forced_layer.unwrap_or(Modifier::from(modifiers)), Action::Click, 1, 0);
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use crate::backend::{ActionSink, InputSource};
+    use super::*;
+
+    struct NoInput;
+
+    impl InputSource for NoInput {
+        fn set_ratchet_mode(&self, _mode: RatchetMode, _smart_shift_threshold: u16) {}
+
+        fn end_stop(&self) {}
+
+        fn restore(&self) {}
+    }
+
+    /// Records keys sent by executor.
+    struct RecordingSink(Rc<RefCell<Vec<u32>>>);
+
+    impl ActionSink for RecordingSink {
+        fn name(&self) -> &'static str {
+            "recording"
+        }
+
+        fn send_key(&self, keysym: u32, _modifiers: u8) {
+            self.0.borrow_mut().push(keysym);
+        }
+
+        fn scroll(&self, _amount: i32) {}
+    }
+
+    fn executor(keys: &Rc<RefCell<Vec<u32>>>) -> Executor {
+        Executor {
+            backends: Backends {
+                input: Box::new(NoInput),
+                focus: None,
+                sink: Box::new(RecordingSink(keys.clone())),
+            },
+            ratchet_mode: RatchetMode::Ratcheted,
+            #[cfg(feature = "mpris")]
+            mpris_handler: MprisHandler::new(),
+            batches: BatchQueue::new(),
+            cooldowns: Cooldowns::new(),
+            subscribers: Subscribers::new(),
+            #[cfg(feature = "scripting")]
+            scripts: ScriptEngine::new(),
+            title: String::new(),
+            event: (Action::Touch, Modifier::None, 0),
+            #[cfg(feature = "scripting")]
+            script_depth: 0,
+            dry_run: false,
+        }
+    }
+
+    #[test]
+    fn nested_toggle_in_cycle_keeps_own_position() {
+        let keys = Rc::new(RefCell::new(Vec::new()));
+        let mut executor = executor(&keys);
+        let mut config = ConfigFile::from_yaml(r#"
+global:
+  mapping:
+    None:
+      click:
+        - Cycle:
+            - - KeyPress: "a"
+            - - Toggle:
+                  on:
+                    - KeyPress: "b"
+                  off:
+                    - KeyPress: "c"
+"#);
+        for _ in 0..4 {
+            executor.run_action(&mut config, Modifier::None, Action::Click, 1, 0);
+        }
+        assert_eq!(*keys.borrow(), vec![0x61, 0x62, 0x61, 0x63]);
+    }
+
+    /// Fails when device is touched.
+    struct UntouchedInput;
+
+    impl InputSource for UntouchedInput {
+        fn set_ratchet_mode(&self, mode: RatchetMode, _smart_shift_threshold: u16) {
+            panic!("Ratchet mode {:?} set in dry run", mode);
+        }
+
+        fn end_stop(&self) {
+            panic!("End stop feedback in dry run");
+        }
+
+        fn restore(&self) {}
+    }
+
+    #[test]
+    fn dry_run_only_changes_daemon_state() {
+        let keys = Rc::new(RefCell::new(Vec::new()));
+        let mut executor = executor(&keys);
+        executor.backends.input = Box::new(UntouchedInput);
+        executor.dry_run = true;
+        let mut config = ConfigFile::from_yaml(r#"
+global:
+  values:
+    opacity: { value: 0.4, min: 0.0, max: 0.5, step: 0.1 }
+  mapping:
+    None:
+      click:
+        - Toggle:
+            on:
+              - KeyPress: "a"
+              - SetRatchet: Free
+            off:
+              - KeyPress: "b"
+      right:
+        - Adjust: opacity
+        - Scroll: 2
+"#);
+        executor.run_action(&mut config, Modifier::None, Action::Click, 1, 0);
+        assert_eq!(executor.ratchet_mode, RatchetMode::Free);
+        for _ in 0..2 {
+            executor.run_action(&mut config, Modifier::None, Action::Right, 1, 1);
+        }
+        assert_eq!(config.adjust_value("opacity", 0).unwrap().value, 0.5);
+        assert!(keys.borrow().is_empty());
+    }
+
+    #[cfg(feature = "scripting")]
+    #[test]
+    fn scripts_queueing_scripts_are_limited() {
+        let keys = Rc::new(RefCell::new(Vec::new()));
+        let mut executor = executor(&keys);
+        executor.scripts = ScriptEngine::from_source(r#"
+            fn again(event) { key("a"); operation(#{ Script: "again" }); }
+        "#);
+        let mut config = ConfigFile::from_yaml("global:\n  mapping: {}\n");
+        executor.execute_commands(&mut config, "global", "None/Click", &[Operation::Script("again".to_owned())], 1);
+        assert_eq!(*keys.borrow(), vec![0x61; MAX_SCRIPT_DEPTH]);
+        assert_eq!(executor.script_depth, 0);
+    }
+
+    #[test]
+    fn ready_when_connected_and_listeners_run() {
+        let mut state = DaemonState {
+            connected: false,
+            health: BTreeMap::new(),
+            battery: None,
+            modifiers: Modifier::None,
+            forced_layer: None,
+        };
+        assert!(!state.is_ready());
+        state.connected = true;
+        assert!(state.is_ready());
+        let health = |running| Health { running, restarts: 1, last_error: Some("Connection lost".to_owned()) };
+        state.health.insert("x11".to_owned(), health(false));
+        assert!(!state.is_ready());
+        state.health.insert("x11".to_owned(), health(true));
+        assert!(state.is_ready());
+    }
+}
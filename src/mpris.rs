@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
+use std::sync::{Arc, Mutex};
+use std::thread::spawn;
+
+use crossbeam_channel::{Receiver, Sender};
+use zbus::blocking::{Connection, MessageIterator, Proxy};
+use zbus::blocking::fdo::DBusProxy;
+use zbus::blocking::proxy::Builder;
+use zbus::MatchRule;
+use zbus::message::Type;
+use zbus::proxy::CacheProperties;
+use zbus::zvariant::OwnedValue;
+
+use crate::config::{MprisCommand, MprisOperation};
+
+const MPRIS_PREFIX: &str = "org.mpris.MediaPlayer2.";
+const MPRIS_PATH: &str = "/org/mpris/MediaPlayer2";
+const PLAYER_INTERFACE: &str = "org.mpris.MediaPlayer2.Player";
+
+struct MprisRequest {
+    operation: MprisOperation,
+    delta: i16,
+}
+
+pub(crate) struct MprisHandler {
+    my_sender: Sender<MprisRequest>,
+}
+
+impl MprisHandler {
+    pub fn new() -> MprisHandler {
+        let (my_sender, my_receiver) = crossbeam_channel::unbounded();
+        let _x = spawn(move || mpris_listener(my_receiver));
+
+        MprisHandler {
+            my_sender,
+        }
+    }
+
+    pub fn execute(&self, operation: &MprisOperation, delta: i16) {
+        let _ = self.my_sender.send(MprisRequest { operation: operation.clone(), delta });
+    }
+}
+
+fn player_proxy<'a>(conn: &Connection, destination: &'a str) -> zbus::Result<Proxy<'a>> {
+    Builder::new(conn).
+        destination(destination)?.
+        path(MPRIS_PATH)?.
+        interface(PLAYER_INTERFACE)?.
+        cache_properties(CacheProperties::No).
+        build()
+}
+
+fn track_active_player(conn: Connection, last_active: Arc<Mutex<Option<String>>>) {
+    let rule = MatchRule::builder().
+        msg_type(Type::Signal).
+        interface("org.freedesktop.DBus.Properties").
+        and_then(|b| b.member("PropertiesChanged")).
+        and_then(|b| b.path(MPRIS_PATH)).
+        map(|b| b.build());
+    let iter = match rule.and_then(|rule| MessageIterator::for_match_rule(rule, &conn, Some(16))) {
+        Ok(iter) => iter,
+        Err(err) => {
+            warn!("Can't watch MPRIS players: {}", err);
+            return;
+        }
+    };
+    for msg in iter.flatten() {
+        let body = msg.body();
+        if let Ok((interface, changed, _)) = body.deserialize::<(String, HashMap<String, OwnedValue>, Vec<String>)>() {
+            if interface != PLAYER_INTERFACE {
+                continue;
+            }
+            let playing = changed.get("PlaybackStatus").
+                and_then(|v| String::try_from(v.clone()).ok()).
+                is_some_and(|v| v == "Playing");
+            if let (true, Some(sender)) = (playing, msg.header().sender()) {
+                debug!("MPRIS player active: {}", sender);
+                *last_active.lock().unwrap() = Some(sender.to_string());
+            }
+        }
+    }
+}
+
+/// Finds bus name of player `name`, players running multiple instances add suffix
+/// like `org.mpris.MediaPlayer2.vlc.instance1234`.
+fn named_player(players: Vec<String>, name: &str) -> Option<String> {
+    let full_name = format!("{}{}", MPRIS_PREFIX, name);
+    players.into_iter().find(|p| *p == full_name || p.starts_with(&format!("{}.", full_name)))
+}
+
+fn find_player(conn: &Connection, name: Option<&str>, last_active: &Mutex<Option<String>>) -> Option<String> {
+    let players: Vec<String> = DBusProxy::new(conn).
+        and_then(|p| p.list_names().map_err(zbus::Error::from)).
+        map(|names| names.into_iter().
+            map(|n| n.to_string()).
+            filter(|n| n.starts_with(MPRIS_PREFIX)).
+            collect()).
+        unwrap_or_default();
+
+    if let Some(name) = name {
+        return named_player(players, name);
+    }
+
+    if let Some(active) = last_active.lock().unwrap().clone() {
+        let has_owner = DBusProxy::new(conn).
+            and_then(|p| p.name_has_owner(active.as_str().try_into()?).map_err(zbus::Error::from)).
+            unwrap_or(false);
+        if has_owner {
+            return Some(active);
+        }
+    }
+
+    let status = |player: &String| player_proxy(conn, player).
+        and_then(|p| p.get_property::<String>("PlaybackStatus")).
+        unwrap_or_default();
+    players.iter().find(|p| status(p) == "Playing").
+        or_else(|| players.iter().find(|p| status(p) == "Paused")).
+        or_else(|| players.first()).
+        cloned()
+}
+
+/// Returns `Seek` offset in microseconds for `delta` rotation steps.
+fn seek_offset(seconds: f64, delta: i16) -> i64 {
+    (seconds * delta as f64 * 1_000_000.0) as i64
+}
+
+fn adjusted_volume(volume: f64, step: f64, delta: i16) -> f64 {
+    (volume + step * delta as f64).clamp(0.0, 1.0)
+}
+
+fn execute_command(proxy: &Proxy, command: &MprisCommand, delta: i16) -> zbus::Result<()> {
+    match command {
+        MprisCommand::PlayPause => proxy.call_method("PlayPause", &()).map(|_| ()),
+        MprisCommand::Play => proxy.call_method("Play", &()).map(|_| ()),
+        MprisCommand::Pause => proxy.call_method("Pause", &()).map(|_| ()),
+        MprisCommand::Stop => proxy.call_method("Stop", &()).map(|_| ()),
+        MprisCommand::Next => proxy.call_method("Next", &()).map(|_| ()),
+        MprisCommand::Previous => proxy.call_method("Previous", &()).map(|_| ()),
+        MprisCommand::Seek(seconds) => proxy.call_method("Seek", &(seek_offset(*seconds, delta), )).map(|_| ()),
+        MprisCommand::Volume(step) => {
+            let volume: f64 = proxy.get_property("Volume")?;
+            proxy.set_property("Volume", adjusted_volume(volume, *step, delta)).map_err(zbus::Error::from)
+        }
+    }
+}
+
+fn mpris_listener(receiver: Receiver<MprisRequest>) {
+    let conn = match Connection::session() {
+        Ok(conn) => conn,
+        Err(err) => {
+            warn!("Can't connect to session bus: {}", err);
+            return;
+        }
+    };
+    let last_active = Arc::new(Mutex::new(None));
+    {
+        let conn = conn.clone();
+        let last_active = last_active.clone();
+        let _x = spawn(move || track_active_player(conn, last_active));
+    }
+
+    for MprisRequest { operation, delta } in receiver {
+        if let Some(player) = find_player(&conn, operation.player.as_deref(), &last_active) {
+            debug!("MPRIS {:?} {} on {}", operation.command, delta, player);
+            if let Err(err) = player_proxy(&conn, &player).
+                and_then(|proxy| execute_command(&proxy, &operation.command, delta))
+            {
+                warn!("MPRIS call to {} failed: {}", player, err);
+            }
+        } else {
+            info!("No MPRIS player for {:?}", operation);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn player_names() {
+        let players = vec!["org.mpris.MediaPlayer2.spotifyd".to_owned(),
+                           "org.mpris.MediaPlayer2.vlc.instance1234".to_owned(),
+                           "org.mpris.MediaPlayer2.spotify".to_owned()];
+        assert_eq!(named_player(players.clone(), "spotify").as_deref(), Some("org.mpris.MediaPlayer2.spotify"));
+        assert_eq!(named_player(players.clone(), "vlc").as_deref(), Some("org.mpris.MediaPlayer2.vlc.instance1234"));
+        assert_eq!(named_player(players, "mpv"), None);
+    }
+
+    #[test]
+    fn seek_and_volume_steps() {
+        assert_eq!(seek_offset(5.0, -2), -10_000_000);
+        assert_eq!(seek_offset(0.5, 3), 1_500_000);
+        assert_eq!(adjusted_volume(0.5, 0.05, 2), 0.6);
+        assert_eq!(adjusted_volume(0.95, 0.05, 3), 1.0);
+        assert_eq!(adjusted_volume(0.05, 0.05, -3), 0.0);
+    }
+
+    #[test]
+    fn operation_from_config() {
+        let operation: crate::config::Operation =
+            serde_yaml::from_str("Mpris: { command: { Seek: 10.0 }, player: spotify }").unwrap();
+        assert!(matches!(operation, crate::config::Operation::Mpris(MprisOperation {
+            command: MprisCommand::Seek(seconds), player: Some(ref player) }) if seconds == 10.0 && player == "spotify"));
+    }
+}
@@ -0,0 +1,266 @@
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Child, Command, Stdio};
+use std::thread::{JoinHandle, spawn};
+
+use crossbeam_channel::{Sender, TrySendError};
+use serde::Deserialize;
+
+use crate::config::Operation;
+use crate::control::Reply;
+use crate::StateChanges;
+
+/// Lines queued for plugin that doesn't read its stdin, newer ones are dropped.
+const QUEUE_SIZE: usize = 256;
+
+/// Messages read from plugin's stdout, one JSON object per line.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum PluginMessage {
+    /// Operations to execute, written as in config.
+    Operations(Vec<Operation>),
+    /// While claimed, crown events are handled only by the plugin.
+    Claim(bool),
+    /// Plugin closed its stdout.
+    #[serde(skip)]
+    Exited,
+}
+
+struct Plugin {
+    command: String,
+    child: Child,
+    /// Lines written to plugin's stdin by its writer thread, so slow plugin doesn't block main loop.
+    writer: Option<Sender<String>>,
+    /// Set while queue is full, to log dropped events once.
+    dropping: bool,
+    claimed: bool,
+    _reader: JoinHandle<()>,
+}
+
+/// Helper processes receiving crown events and focus changes on stdin as
+/// `{"event": ...}` lines, their replies are passed to main loop as `StateChanges::Plugin`.
+pub(crate) struct PluginHandler {
+    plugins: Vec<Plugin>,
+}
+
+fn spawn_plugin(index: usize, command: &str, sender: Sender<StateChanges>) -> std::io::Result<Plugin> {
+    let mut parts = command.split_ascii_whitespace();
+    let mut child = Command::new(parts.next().unwrap_or_default()).
+        args(parts).
+        stdin(Stdio::piped()).
+        stdout(Stdio::piped()).
+        spawn()?;
+    let mut stdin = child.stdin.take().ok_or_else(|| std::io::Error::other("Missing plugin stdin"))?;
+    let stdout = child.stdout.take().ok_or_else(|| std::io::Error::other("Missing plugin stdout"))?;
+    let (writer, lines) = crossbeam_channel::bounded::<String>(QUEUE_SIZE);
+    let name = command.to_owned();
+    spawn(move || {
+        for line in lines.iter() {
+            if let Err(err) = writeln!(stdin, "{}", line).and_then(|_| stdin.flush()) {
+                warn!("Can't write to plugin {}: {}", name, err);
+                return;
+            }
+        }
+    });
+    let name = command.to_owned();
+    let _reader = spawn(move || {
+        for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str::<PluginMessage>(&line) {
+                Ok(message) => {
+                    if sender.send(StateChanges::Plugin { plugin: index, message }).is_err() {
+                        return;
+                    }
+                }
+                Err(err) => warn!("Invalid message from plugin {}: {}", name, err),
+            }
+        }
+        debug!("Plugin {} closed its output", name);
+        let _ = sender.send(StateChanges::Plugin { plugin: index, message: PluginMessage::Exited });
+    });
+
+    Ok(Plugin {
+        command: command.to_owned(),
+        child,
+        writer: Some(writer),
+        dropping: false,
+        claimed: false,
+        _reader,
+    })
+}
+
+impl PluginHandler {
+    pub fn new(commands: &[String], sender: &Sender<StateChanges>) -> PluginHandler {
+        let mut plugins = Vec::new();
+        for command in commands {
+            match spawn_plugin(plugins.len(), command, sender.clone()) {
+                Ok(plugin) => plugins.push(plugin),
+                Err(err) => error!("Can't start plugin {}: {}", command, err),
+            }
+        }
+
+        PluginHandler {
+            plugins,
+        }
+    }
+
+    /// Forwards crown events and focus changes to plugins.
+    pub fn send(&mut self, change: &StateChanges) {
+        if !change.is_crown_event() && !matches!(change, StateChanges::FocusChanged { .. }) {
+            return;
+        }
+        let line = Reply::Event(change).to_line();
+        for plugin in self.plugins.iter_mut() {
+            let res = match plugin.writer {
+                Some(ref writer) => writer.try_send(line.clone()),
+                None => continue,
+            };
+            match res {
+                Ok(()) => plugin.dropping = false,
+                Err(TrySendError::Full(_)) => {
+                    if !plugin.dropping {
+                        warn!("Plugin {} doesn't read events, dropping them", plugin.command);
+                        plugin.dropping = true;
+                    }
+                }
+                Err(TrySendError::Disconnected(_)) => plugin.writer = None,
+            }
+        }
+    }
+
+    /// Returns `true` when some plugin claimed crown events.
+    pub fn claimed(&self) -> bool {
+        self.plugins.iter().any(|plugin| plugin.claimed)
+    }
+
+    /// Handles message from plugin `index`, returns operations it asked for.
+    pub fn handle(&mut self, index: usize, message: PluginMessage) -> Option<Vec<Operation>> {
+        let plugin = self.plugins.get_mut(index)?;
+        debug!("Plugin {}: {:?}", plugin.command, message);
+        match message {
+            PluginMessage::Operations(operations) => return Some(operations),
+            PluginMessage::Claim(claimed) => plugin.claimed = claimed,
+            PluginMessage::Exited => {
+                warn!("Plugin {} exited", plugin.command);
+                plugin.claimed = false;
+                plugin.writer = None;
+                let _ = plugin.child.kill();
+                let _ = plugin.child.wait();
+            }
+        }
+        None
+    }
+}
+
+impl Drop for PluginHandler {
+    fn drop(&mut self) {
+        for plugin in self.plugins.iter_mut() {
+            let _ = plugin.child.kill();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{remove_file, write};
+    use std::path::PathBuf;
+    use std::time::{Duration, Instant};
+
+    use crossbeam_channel::Receiver;
+
+    use super::*;
+
+    /// Writes shell script standing in for a plugin, returns command starting it and its path.
+    fn plugin_script(name: &str, script: &str) -> (String, PathBuf) {
+        let path = std::env::temp_dir().join(format!("crown-plugin-{}-{}.sh", name, std::process::id()));
+        write(&path, script).unwrap();
+        (format!("sh {}", path.display()), path)
+    }
+
+    fn next_message(receiver: &Receiver<StateChanges>) -> (usize, PluginMessage) {
+        match receiver.recv_timeout(Duration::from_secs(5)) {
+            Ok(StateChanges::Plugin { plugin, message }) => (plugin, message),
+            other => panic!("Unexpected plugin reply {:?}", other),
+        }
+    }
+
+    #[test]
+    fn claim_and_operations() {
+        let (command, path) = plugin_script("protocol", r#"
+while read -r line; do
+    case "$line" in
+        *CrownClicked*) echo '{"claim": true}' ;;
+        *CrownRotated*) echo 'not json'; echo '{"operations": [{"Scroll": 2}, {"KeyPress": "Ctrl+Tab"}]}' ;;
+        *FocusChanged*) echo '{"claim": false}'; exit 0 ;;
+    esac
+done
+"#);
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let mut handler = PluginHandler::new(&[command], &sender);
+        assert!(!handler.claimed());
+
+        // Not forwarded to plugins
+        handler.send(&StateChanges::BatteryChanged { level: 50 });
+        handler.send(&StateChanges::CrownClicked { modifiers: 0 });
+        let (plugin, message) = next_message(&receiver);
+        assert_eq!(plugin, 0);
+        assert!(matches!(message, PluginMessage::Claim(true)));
+        assert!(handler.handle(plugin, message).is_none());
+        assert!(handler.claimed());
+
+        handler.send(&StateChanges::CrownRotated { modifiers: 0, amount: 1, notch_amount: 1, pressed: false });
+        let (plugin, message) = next_message(&receiver);
+        let operations = handler.handle(plugin, message).unwrap();
+        assert!(matches!(operations.as_slice(), [Operation::Scroll(2), Operation::KeyPress(_, 4)]));
+
+        handler.send(&StateChanges::FocusChanged {
+            pid: 1,
+            program: "/usr/bin/firefox".to_owned(),
+            sandbox_id: None,
+            cmdline: Vec::new(),
+            class: String::new(),
+            title: String::new(),
+        });
+        let (plugin, message) = next_message(&receiver);
+        assert!(matches!(message, PluginMessage::Claim(false)));
+        handler.handle(plugin, message);
+        assert!(!handler.claimed());
+        let (plugin, message) = next_message(&receiver);
+        assert!(matches!(message, PluginMessage::Exited));
+        handler.handle(plugin, message);
+        // Events for exited plugin are ignored
+        handler.send(&StateChanges::CrownClicked { modifiers: 0 });
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn exit_releases_claim() {
+        let (command, path) = plugin_script("exit", "echo '{\"claim\": true}'\n");
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let mut handler = PluginHandler::new(&[command], &sender);
+        let (plugin, message) = next_message(&receiver);
+        handler.handle(plugin, message);
+        assert!(handler.claimed());
+        let (plugin, message) = next_message(&receiver);
+        assert!(matches!(message, PluginMessage::Exited));
+        handler.handle(plugin, message);
+        assert!(!handler.claimed());
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn plugin_not_reading_stdin_doesnt_block() {
+        let (command, path) = plugin_script("stuck", "sleep 30\n");
+        let (sender, _receiver) = crossbeam_channel::unbounded();
+        let mut handler = PluginHandler::new(&[command], &sender);
+        let started = Instant::now();
+        // Much more than fits into pipe buffer and queue
+        for _ in 0..20000 {
+            handler.send(&StateChanges::CrownRotated { modifiers: 0, amount: 1, notch_amount: 1, pressed: false });
+        }
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(handler.plugins[0].dropping);
+        let _ = remove_file(path);
+    }
+}
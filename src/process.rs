@@ -0,0 +1,313 @@
+use std::fs::{read_dir, read_link, read_to_string};
+use std::time::{Duration, Instant};
+
+const TERMINAL_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Returns path of executable running as `pid` or empty string if it can't be read.
+pub(crate) fn program_path(pid: u32) -> String {
+    if let Ok(path) = read_link(format!("/proc/{:}/exe", pid)) {
+        path.to_string_lossy().to_string()
+    } else {
+        "".to_owned()
+    }
+}
+
+#[cfg(feature = "x11")]
+fn pids() -> impl Iterator<Item = String> {
+    read_dir("/proc").into_iter().flatten().flatten().
+        filter_map(|entry| entry.file_name().into_string().ok()).
+        filter(|name| name.bytes().all(|c| c.is_ascii_digit()))
+}
+
+#[cfg(feature = "x11")]
+fn pid_namespace(pid: &str) -> Option<String> {
+    read_link(format!("/proc/{}/ns/pid", pid)).ok().map(|ns| ns.to_string_lossy().to_string())
+}
+
+/// Returns pid from `NSpid` line of `/proc/<pid>/status`, which is the pid
+/// as seen in its own (innermost) pid namespace.
+#[cfg(feature = "x11")]
+fn parse_namespaced_pid(status: &str) -> Option<u32> {
+    status.lines().
+        find_map(|line| line.strip_prefix("NSpid:")).
+        and_then(|pids| pids.split_whitespace().last()?.parse().ok())
+}
+
+#[cfg(feature = "x11")]
+fn namespaced_pid(pid: &str) -> Option<u32> {
+    parse_namespaced_pid(&read_to_string(format!("/proc/{}/status", pid)).ok()?)
+}
+
+/// Translates `pid` reported by a window to pid in our namespace, `client_pid`
+/// is pid of process owning the window connection, which can run in a sandbox
+/// with its own pid namespace (Flatpak, Snap).
+#[cfg(feature = "x11")]
+pub(crate) fn translate_pid(pid: u32, client_pid: u32) -> u32 {
+    let client = client_pid.to_string();
+    let namespace = pid_namespace(&client);
+    if pid == client_pid || namespace.is_none() || namespace == pid_namespace("self") {
+        return pid;
+    }
+    if namespaced_pid(&client) == Some(pid) {
+        return client_pid;
+    }
+    pids().
+        find(|name| namespaced_pid(name) == Some(pid) && pid_namespace(name) == namespace).
+        and_then(|name| name.parse().ok()).
+        unwrap_or(client_pid)
+}
+
+/// Returns `name` from `[Application]` section of `.flatpak-info`.
+#[cfg(any(feature = "x11", feature = "sway", feature = "hyprland", feature = "kde"))]
+fn parse_flatpak_info(info: &str) -> Option<String> {
+    let mut in_application = false;
+    for line in info.lines().map(str::trim) {
+        if line.starts_with('[') {
+            in_application = line == "[Application]";
+        } else if let Some(name) = line.strip_prefix("name=").filter(|_| in_application) {
+            return Some(name.to_owned());
+        }
+    }
+    None
+}
+
+/// Extracts application id from systemd scope names in `/proc/<pid>/cgroup` like
+/// `app-flatpak-org.gimp.GIMP-1234.scope` or `snap.firefox.firefox-0f3b….scope`.
+#[cfg(any(feature = "x11", feature = "sway", feature = "hyprland", feature = "kde"))]
+fn parse_sandbox_scope(cgroup: &str) -> Option<String> {
+    cgroup.lines().
+        filter_map(|line| line.rsplit('/').next()?.strip_suffix(".scope")).
+        find_map(|scope| {
+            if let Some(app) = scope.strip_prefix("app-flatpak-") {
+                app.rsplit_once('-').map(|(id, _)| id.to_owned())
+            } else if let Some(snap) = scope.strip_prefix("snap.") {
+                snap.split('.').next().map(|name| name.to_owned())
+            } else {
+                None
+            }
+        })
+}
+
+/// Returns Flatpak application id or Snap name when `pid` runs in a sandbox.
+#[cfg(any(feature = "x11", feature = "sway", feature = "hyprland", feature = "kde"))]
+pub(crate) fn sandbox_id(pid: u32) -> Option<String> {
+    if pid == 0 {
+        return None;
+    }
+    read_to_string(format!("/proc/{}/root/.flatpak-info", pid)).ok().
+        and_then(|info| parse_flatpak_info(&info)).
+        or_else(|| parse_sandbox_scope(&read_to_string(format!("/proc/{}/cgroup", pid)).ok()?))
+}
+
+/// Returns arguments of process `pid`, empty when it can't be read.
+pub(crate) fn command_line(pid: u32) -> Vec<String> {
+    if pid == 0 {
+        return Vec::new();
+    }
+    read_to_string(format!("/proc/{}/cmdline", pid)).
+        map(|cmdline| cmdline.split_terminator('\0').map(|arg| arg.to_owned()).collect()).
+        unwrap_or_default()
+}
+
+fn file_name(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+/// Returns first argument that isn't an option, skipping values of `options_with_value`.
+fn first_operand<'a>(args: &'a [String], options_with_value: &[&str]) -> Option<&'a str> {
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if options_with_value.contains(&arg.as_str()) {
+            args.next();
+        } else if !arg.starts_with('-') {
+            return Some(arg);
+        }
+    }
+    None
+}
+
+/// Returns name of script or application run by interpreter in `cmdline`: python
+/// script or module, jar file or main class for java and app directory of electron app.
+pub(crate) fn script_name(cmdline: &[String]) -> Option<String> {
+    let (program, args) = cmdline.split_first()?;
+    let program = file_name(program);
+
+    if let Some(asar) = args.iter().find(|arg| arg.ends_with(".asar")) {
+        let mut dirs = asar.rsplit('/').skip(1).filter(|dir| *dir != "resources");
+        return dirs.next().filter(|dir| !dir.is_empty()).map(|dir| dir.to_owned());
+    }
+    if program.starts_with("python") {
+        if let Some(idx) = args.iter().position(|arg| arg == "-m") {
+            return args.get(idx + 1).cloned();
+        }
+        if args.iter().any(|arg| arg == "-c") {
+            return None;
+        }
+        return first_operand(args, &["-W", "-X", "-Q"]).map(|script| script.to_owned());
+    }
+    if program == "java" {
+        if let Some(idx) = args.iter().position(|arg| arg == "-jar") {
+            return args.get(idx + 1).cloned();
+        }
+        if let Some(idx) = args.iter().position(|arg| arg == "-m" || arg == "--module") {
+            return args.get(idx + 1).map(|module| module.rsplit('/').next().unwrap_or(module).to_owned());
+        }
+        return first_operand(args, &["-cp", "-classpath", "--class-path", "-p", "--module-path"]).
+            map(|class| class.to_owned());
+    }
+    if ["perl", "ruby", "node", "bash", "sh"].contains(&program) {
+        if args.iter().any(|arg| arg == "-e" || arg == "-c") {
+            return None;
+        }
+        return first_operand(args, &["-I", "-r"]).map(|script| script.to_owned());
+    }
+    None
+}
+
+struct Stat {
+    tty_nr: i32,
+    tpgid: i32,
+    start_time: u64,
+}
+
+fn parse_stat(stat: &str) -> Option<Stat> {
+    // Process name is in parentheses and can contain spaces, fields start with state after it
+    let fields: Vec<&str> = stat.get(stat.rfind(')')? + 2..)?.split_whitespace().collect();
+    Some(Stat {
+        tty_nr: fields.get(4)?.parse().ok()?,
+        tpgid: fields.get(5)?.parse().ok()?,
+        start_time: fields.get(19)?.parse().ok()?,
+    })
+}
+
+fn read_stat(pid: u32) -> Option<Stat> {
+    parse_stat(&read_to_string(format!("/proc/{}/stat", pid)).ok()?)
+}
+
+/// Returns children of all threads of process `pid`.
+fn children(pid: u32) -> Vec<u32> {
+    read_dir(format!("/proc/{}/task", pid)).into_iter().flatten().flatten().
+        filter_map(|task| read_to_string(task.path().join("children")).ok()).
+        flat_map(|children| children.split_whitespace().filter_map(|c| c.parse().ok()).collect::<Vec<u32>>()).
+        collect()
+}
+
+/// Returns foreground process of terminal emulator `pid`, it's the leader of foreground
+/// process group (`tpgid`) of pty of terminal's children, when terminal has multiple
+/// tabs the most recently started foreground process is used.
+pub(crate) fn terminal_foreground(pid: u32) -> Option<u32> {
+    if pid == 0 {
+        return None;
+    }
+    children(pid).into_iter().
+        filter_map(read_stat).
+        filter(|stat| stat.tty_nr != 0 && stat.tpgid > 0).
+        filter_map(|shell| read_stat(shell.tpgid as u32).map(|leader| (shell.tpgid as u32, leader))).
+        max_by_key(|(_, leader)| leader.start_time).
+        map(|(pid, _)| pid)
+}
+
+/// Follows foreground process of the focused terminal emulator.
+pub(crate) struct TerminalTracker {
+    terminal_pid: u32,
+    foreground_pid: Option<u32>,
+    next_poll: Option<Instant>,
+}
+
+impl TerminalTracker {
+    pub fn new() -> TerminalTracker {
+        TerminalTracker {
+            terminal_pid: 0,
+            foreground_pid: None,
+            next_poll: None,
+        }
+    }
+
+    /// Starts following focused process `pid`, returns its foreground process
+    /// when it's a terminal emulator.
+    pub fn focus(&mut self, pid: u32) -> Option<u32> {
+        self.terminal_pid = pid;
+        self.foreground_pid = terminal_foreground(pid);
+        self.next_poll = self.foreground_pid.map(|_| Instant::now() + TERMINAL_POLL_INTERVAL);
+        self.foreground_pid
+    }
+
+    pub fn next_poll(&self) -> Option<Instant> {
+        self.next_poll
+    }
+
+    /// Checks foreground process again when poll is due, returns `Some` with new
+    /// foreground process when it changed.
+    pub fn poll(&mut self) -> Option<Option<u32>> {
+        self.next_poll.filter(|at| *at <= Instant::now())?;
+        let foreground_pid = terminal_foreground(self.terminal_pid);
+        self.next_poll = Some(Instant::now() + TERMINAL_POLL_INTERVAL);
+        if foreground_pid != self.foreground_pid {
+            self.foreground_pid = foreground_pid;
+            Some(foreground_pid)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(any(feature = "x11", feature = "sway", feature = "hyprland", feature = "kde"))]
+    #[test]
+    fn flatpak_info() {
+        let info = "[Instance]\nname=instance\n\n[Application]\nname=org.gimp.GIMP\nruntime=runtime/org.gnome.Platform\n";
+        assert_eq!(parse_flatpak_info(info), Some("org.gimp.GIMP".to_owned()));
+        assert_eq!(parse_flatpak_info("[Runtime]\nname=org.gnome.Platform\n"), None);
+    }
+
+    #[cfg(any(feature = "x11", feature = "sway", feature = "hyprland", feature = "kde"))]
+    #[test]
+    fn sandbox_scopes() {
+        let flatpak = "0::/user.slice/user-1000.slice/user@1000.service/app.slice/app-flatpak-org.gimp.GIMP-1234.scope\n";
+        assert_eq!(parse_sandbox_scope(flatpak), Some("org.gimp.GIMP".to_owned()));
+        let snap = "0::/user.slice/user-1000.slice/user@1000.service/app.slice/snap.firefox.firefox-0f3b1c.scope\n";
+        assert_eq!(parse_sandbox_scope(snap), Some("firefox".to_owned()));
+        let plain = "0::/user.slice/user-1000.slice/user@1000.service/app.slice/app-gnome-org.gnome.Nautilus-42.scope\n";
+        assert_eq!(parse_sandbox_scope(plain), None);
+    }
+
+    #[cfg(feature = "x11")]
+    #[test]
+    fn namespaced_pid_is_innermost() {
+        assert_eq!(parse_namespaced_pid("Name:\tgimp\nPid:\t4321\nNSpid:\t4321\t2\n"), Some(2));
+        assert_eq!(parse_namespaced_pid("Name:\tbash\nNSpid:\t100\n"), Some(100));
+        assert_eq!(parse_namespaced_pid("Name:\tbash\n"), None);
+    }
+
+    #[test]
+    fn stat_with_spaces_in_name() {
+        let stat = parse_stat("4242 (tmux: client) S 4200 4242 4200 34817 4250 4194304 577 0 0 0 3 1 0 0 \
+                               20 0 1 0 123456 9830400 1021 18446744073709551615").unwrap();
+        assert_eq!((stat.tty_nr, stat.tpgid, stat.start_time), (34817, 4250, 123456));
+        assert!(parse_stat("4242 (truncated) S 1").is_none());
+    }
+
+    fn script(cmdline: &str) -> Option<String> {
+        script_name(&cmdline.split(' ').map(|arg| arg.to_owned()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn interpreted_scripts() {
+        assert_eq!(script("/usr/bin/python3 -u /usr/bin/anki"), Some("/usr/bin/anki".to_owned()));
+        assert_eq!(script("python3 -W ignore tool.py --verbose"), Some("tool.py".to_owned()));
+        assert_eq!(script("python3.12 -m http.server 8000"), Some("http.server".to_owned()));
+        assert_eq!(script("python3 -c print(1)"), None);
+        assert_eq!(script("/usr/bin/java -Xmx1g -jar /opt/app/app.jar"), Some("/opt/app/app.jar".to_owned()));
+        assert_eq!(script("java -cp lib/a.jar org.example.Main"), Some("org.example.Main".to_owned()));
+        assert_eq!(script("java --module-path mods -m org.example/org.example.Main"),
+                   Some("org.example.Main".to_owned()));
+        assert_eq!(script("node --inspect server.js"), Some("server.js".to_owned()));
+        assert_eq!(script("bash -c true"), None);
+        assert_eq!(script("/usr/lib/electron/electron /usr/lib/code/resources/app.asar"), Some("code".to_owned()));
+        assert_eq!(script("/usr/bin/gimp image.png"), None);
+        assert_eq!(script(""), None);
+    }
+}
@@ -0,0 +1,231 @@
+use std::cell::RefCell;
+use std::fs::metadata;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::time::{Duration, Instant, SystemTime};
+
+use directories::ProjectDirs;
+use rhai::{CallFnOptions, Dynamic, Engine, EvalAltResult, Map, Scope, AST};
+use serde::Serialize;
+
+use crate::config::{parse_key, Action, Modifier, Operation, RatchetMode};
+
+/// Crown event passed to script functions as object map.
+#[derive(Debug, Serialize)]
+pub(crate) struct ScriptEvent<'a> {
+    pub(crate) action: Action,
+    /// Rotation amount, notches or raw amount depending on ratchet mode.
+    pub(crate) amount: i16,
+    pub(crate) notch: i16,
+    pub(crate) modifiers: Modifier,
+    pub(crate) ratchet_mode: RatchetMode,
+    pub(crate) app: &'a str,
+    pub(crate) class: &'a str,
+    pub(crate) title: &'a str,
+    pub(crate) profile: &'a str,
+}
+
+/// Operations queued by host functions together with rotation amount they are executed with.
+type Queue = Rc<RefCell<Vec<(Operation, i16)>>>;
+
+/// Runs functions from `config.rhai` stored next to `config.yaml`, script is reloaded
+/// when it changes. Host functions queue operations, which are executed after script returns.
+pub(crate) struct ScriptEngine {
+    engine: Engine,
+    ast: Option<AST>,
+    path: Option<PathBuf>,
+    mtime: Option<SystemTime>,
+    last_mtime_check: Instant,
+    queue: Queue,
+}
+
+fn register_api(engine: &mut Engine, queue: &Queue) {
+    let push = |queue: &Queue| {
+        let queue = queue.clone();
+        move |operation: Operation, delta: i16| queue.borrow_mut().push((operation, delta))
+    };
+
+    let p = push(queue);
+    engine.register_fn("key", move |key: &str| -> Result<(), Box<EvalAltResult>> {
+        let (keysym, modifiers) = parse_key(key)?;
+        p(Operation::KeyPress(keysym, modifiers), 1);
+        Ok(())
+    });
+    let p = push(queue);
+    engine.register_fn("run", move |command: &str| p(Operation::Execute(command.to_owned()), 1));
+    let p = push(queue);
+    engine.register_fn("batch", move |command: &str, idle: i64, delta: i64| {
+        p(Operation::Batch { command: command.to_owned(), idle: idle.max(0) as u64 }, delta as i16)
+    });
+    let p = push(queue);
+    engine.register_fn("scroll", move |amount: i64| p(Operation::Scroll(amount as i32), 1));
+    let p = push(queue);
+    engine.register_fn("adjust", move |name: &str, delta: i64| p(Operation::Adjust(name.to_owned()), delta as i16));
+    let p = push(queue);
+    engine.register_fn("set_ratchet", move |mode: &str| -> Result<(), Box<EvalAltResult>> {
+        let mode: RatchetMode = rhai::serde::from_dynamic(&Dynamic::from(mode.to_owned()))?;
+        p(Operation::SetRatchet(mode), 1);
+        Ok(())
+    });
+    let p = push(queue);
+    engine.register_fn("toggle_ratchet", move || p(Operation::ToggleRatchet, 1));
+    // Any operation written as in config, e.g. `operation(#{ Mpris: #{ command: "PlayPause" } })`
+    let p = push(queue);
+    engine.register_fn("operation", move |operation: Dynamic| -> Result<(), Box<EvalAltResult>> {
+        p(rhai::serde::from_dynamic(&operation)?, 1);
+        Ok(())
+    });
+
+    let state = Rc::new(RefCell::new(Map::new()));
+    let s = state.clone();
+    engine.register_fn("state_get", move |name: &str| s.borrow().get(name).cloned().unwrap_or(Dynamic::UNIT));
+    engine.register_fn("state_set", move |name: &str, value: Dynamic| {
+        state.borrow_mut().insert(name.into(), value);
+    });
+}
+
+impl ScriptEngine {
+    pub fn new() -> ScriptEngine {
+        let queue = Queue::default();
+        let mut engine = Engine::new();
+        // Script runs on main loop, endless loop or recursion must not block it
+        engine.set_max_operations(1_000_000);
+        engine.set_max_call_levels(32);
+        engine.set_max_expr_depths(64, 32);
+        register_api(&mut engine, &queue);
+
+        ScriptEngine {
+            engine,
+            ast: None,
+            path: ProjectDirs::from("org", "prefiks", "crown-controller").
+                map(|dirs| dirs.config_dir().join("config.rhai")),
+            mtime: None,
+            last_mtime_check: Instant::now() - Duration::from_secs(1000),
+            queue,
+        }
+    }
+
+    /// Returns engine running `source` instead of `config.rhai`.
+    #[cfg(test)]
+    pub(crate) fn from_source(source: &str) -> ScriptEngine {
+        let mut scripts = ScriptEngine::new();
+        scripts.path = None;
+        scripts.ast = Some(scripts.engine.compile(source).unwrap());
+        scripts
+    }
+
+    fn maybe_load(&mut self) {
+        if let Some(ref path) = self.path {
+            if self.last_mtime_check.elapsed() > Duration::from_secs(1) {
+                let mtime = metadata(path).and_then(|meta| meta.modified()).ok();
+                if mtime != self.mtime {
+                    self.ast = match mtime.map(|_| self.engine.compile_file(path.clone())) {
+                        Some(Ok(ast)) => {
+                            info!("Loaded script {:?}", path);
+                            Some(ast)
+                        }
+                        Some(Err(err)) => {
+                            error!("Can't load script: {}", err);
+                            None
+                        }
+                        None => None,
+                    };
+                    self.mtime = mtime;
+                }
+                self.last_mtime_check = Instant::now();
+            }
+        }
+    }
+
+    /// Calls `function` with `event`, returns operations queued by it with rotation
+    /// amounts they should be executed with.
+    pub fn call(&mut self, function: &str, event: &ScriptEvent) -> Vec<(Operation, i16)> {
+        self.maybe_load();
+        let ast = match self.ast {
+            Some(ref ast) => ast,
+            None => {
+                warn!("Script function {} called, but no script is loaded", function);
+                return Vec::new();
+            }
+        };
+        let res = rhai::serde::to_dynamic(event).and_then(|event| {
+            let options = CallFnOptions::new().eval_ast(false);
+            self.engine.call_fn_with_options::<Dynamic>(options, &mut Scope::new(), ast, function, (event, ))
+        });
+        let operations = self.queue.take();
+        match res {
+            Ok(_) => operations,
+            Err(err) => {
+                error!("Script function {} failed: {}", function, err);
+                Vec::new()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event() -> ScriptEvent<'static> {
+        ScriptEvent {
+            action: Action::Right,
+            amount: 3,
+            notch: 1,
+            modifiers: Modifier::Ctrl,
+            ratchet_mode: RatchetMode::Free,
+            app: "/usr/bin/gimp",
+            class: "Gimp",
+            title: "image.png",
+            profile: "gimp",
+        }
+    }
+
+    #[test]
+    fn queued_operations_are_returned() {
+        let mut scripts = ScriptEngine::from_source(r#"
+            fn zoom(event) {
+                if event.modifiers == "Ctrl" && event.action == "right" {
+                    key("ctrl+plus");
+                    scroll(event.amount * 2);
+                    adjust("volume", -1);
+                    operation(#{ SetRatchet: "Ratcheted" });
+                }
+            }
+        "#);
+        let operations = scripts.call("zoom", &event());
+        assert!(matches!(operations.as_slice(), [
+            (Operation::KeyPress(0x2b, 4), 1),
+            (Operation::Scroll(6), 1),
+            (Operation::Adjust(ref name), -1),
+            (Operation::SetRatchet(RatchetMode::Ratcheted), 1),
+        ] if name == "volume"));
+    }
+
+    #[test]
+    fn failed_function_queues_nothing() {
+        let mut scripts = ScriptEngine::from_source(r#"
+            fn unknown_key(event) { scroll(1); key("ctrl+nokey"); }
+            fn endless(event) { scroll(1); loop {} }
+            fn recursive(event) { recursive(event) }
+        "#);
+        assert!(scripts.call("unknown_key", &event()).is_empty());
+        assert!(scripts.call("endless", &event()).is_empty());
+        assert!(scripts.call("recursive", &event()).is_empty());
+        assert!(scripts.call("missing", &event()).is_empty());
+    }
+
+    #[test]
+    fn state_is_kept_between_calls() {
+        let mut scripts = ScriptEngine::from_source(r#"
+            fn count(event) {
+                let calls = state_get("calls");
+                let calls = if calls == () { 1 } else { calls + 1 };
+                state_set("calls", calls);
+                scroll(calls);
+            }
+        "#);
+        scripts.call("count", &event());
+        assert!(matches!(scripts.call("count", &event()).as_slice(), [(Operation::Scroll(2), 1)]));
+    }
+}
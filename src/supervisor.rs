@@ -0,0 +1,106 @@
+use std::any::Any;
+use std::io;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::thread::{sleep, Builder, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::Sender;
+use serde::Serialize;
+
+use crate::StateChanges;
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// Backoff starts from `INITIAL_BACKOFF` again when task ran at least this long.
+const STABLE_RUN: Duration = Duration::from_secs(60);
+
+/// State of supervised backend thread reported in status.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub(crate) struct Health {
+    pub(crate) running: bool,
+    pub(crate) restarts: u32,
+    pub(crate) last_error: Option<String>,
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    panic.downcast_ref::<&str>().copied().
+        or_else(|| panic.downcast_ref::<String>().map(String::as_str)).
+        unwrap_or("unknown cause")
+}
+
+/// Runs `task` in thread `name`, when it returns or panics the cause is logged,
+/// reported as `StateChanges::BackendHealth` and task is started again after backoff.
+pub(crate) fn supervise<F>(name: &'static str, sender: Sender<StateChanges>, mut task: F) -> io::Result<JoinHandle<()>>
+    where F: FnMut() -> io::Result<()> + Send + 'static
+{
+    Builder::new().name(name.to_owned()).spawn(move || {
+        let mut backoff = INITIAL_BACKOFF;
+        let mut health = Health {
+            running: true,
+            restarts: 0,
+            last_error: None,
+        };
+        loop {
+            let started = Instant::now();
+            let cause = match catch_unwind(AssertUnwindSafe(&mut task)) {
+                Ok(Ok(())) => "exited".to_owned(),
+                Ok(Err(err)) => err.to_string(),
+                Err(panic) => format!("panicked: {}", panic_message(panic.as_ref())),
+            };
+            if started.elapsed() >= STABLE_RUN {
+                backoff = INITIAL_BACKOFF;
+            }
+            error!(target: name, "Listener stopped: {}, restarting in {}s", cause, backoff.as_secs());
+            health.running = false;
+            health.last_error = Some(cause);
+            if sender.send(StateChanges::BackendHealth { backend: name, health: health.clone() }).is_err() {
+                return;
+            }
+
+            sleep(backoff);
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+            health.running = true;
+            health.restarts += 1;
+            if sender.send(StateChanges::BackendHealth { backend: name, health: health.clone() }).is_err() {
+                return;
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+
+    use super::*;
+
+    #[test]
+    fn failing_task_is_restarted() {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let (stop_sender, stop_receiver) = crossbeam_channel::unbounded::<()>();
+        let mut runs = 0;
+        let _x = supervise("test", sender, move || {
+            runs += 1;
+            match runs {
+                1 => Err(io::Error::new(ErrorKind::NotFound, "Crown device not found")),
+                2 => panic!("broken"),
+                // Runs until the test ends
+                _ => {
+                    let _ = stop_receiver.recv();
+                    Ok(())
+                }
+            }
+        }).unwrap();
+
+        let health = || match receiver.recv_timeout(Duration::from_secs(5)).unwrap() {
+            StateChanges::BackendHealth { backend: "test", health } => health,
+            change => panic!("Unexpected {:?}", change),
+        };
+        let stopped = Health { running: false, restarts: 0, last_error: Some("Crown device not found".to_owned()) };
+        assert_eq!(health(), stopped);
+        assert_eq!(health(), Health { running: true, restarts: 1, ..stopped.clone() });
+        assert_eq!(health(), Health { running: false, restarts: 1, last_error: Some("panicked: broken".to_owned()) });
+        assert_eq!(health(), Health { running: true, restarts: 2, last_error: Some("panicked: broken".to_owned()) });
+        drop(stop_sender);
+    }
+}
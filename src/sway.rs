@@ -0,0 +1,252 @@
+use std::env;
+use std::io::{self, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use crossbeam_channel::Sender;
+use serde::Deserialize;
+
+use crate::process::{command_line, program_path, sandbox_id};
+use crate::backend::FocusSource;
+use crate::supervisor::supervise;
+use crate::StateChanges;
+
+const MAGIC: &[u8] = b"i3-ipc";
+const GET_TREE: u32 = 4;
+const SUBSCRIBE: u32 = 2;
+const WINDOW_EVENT: u32 = 0x8000_0003;
+
+#[derive(Debug, Deserialize, Default)]
+struct WindowProperties {
+    #[serde(default)]
+    class: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Node {
+    #[serde(default)]
+    pid: Option<u32>,
+    /// X11 window id, i3 reports it instead of pid.
+    #[serde(default)]
+    window: Option<u32>,
+    #[serde(default)]
+    app_id: Option<String>,
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    focused: bool,
+    #[serde(default)]
+    window_properties: Option<WindowProperties>,
+    #[serde(default)]
+    nodes: Vec<Node>,
+    #[serde(default)]
+    floating_nodes: Vec<Node>,
+}
+
+impl Node {
+    fn find_focused(&self) -> Option<&Node> {
+        if self.focused {
+            return Some(self);
+        }
+        self.nodes.iter().chain(self.floating_nodes.iter()).find_map(|n| n.find_focused())
+    }
+
+    /// Returns focus change for this window, `window_pid` finds pid of X11 windows
+    /// when compositor doesn't report it.
+    fn focus_changed(&self, window_pid: impl Fn(u32) -> Option<u32>) -> StateChanges {
+        let pid = self.pid.filter(|pid| *pid != 0).
+            or_else(|| self.window.and_then(window_pid)).
+            unwrap_or(0);
+        StateChanges::FocusChanged {
+            pid,
+            program: if pid != 0 { program_path(pid) } else { "".to_owned() },
+            sandbox_id: sandbox_id(pid),
+            cmdline: command_line(pid),
+            class: self.app_id.clone().
+                or_else(|| self.window_properties.as_ref().and_then(|p| p.class.clone())).
+                unwrap_or_default(),
+            title: self.name.clone().unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct WindowEvent {
+    change: String,
+    container: Node,
+}
+
+/// Returns path of sway or i3 IPC socket of the running session.
+fn socket_path() -> Option<PathBuf> {
+    env::var_os("SWAYSOCK").or_else(|| env::var_os("I3SOCK")).map(PathBuf::from).
+        or_else(i3_socket_path)
+}
+
+/// i3 doesn't export `I3SOCK`, it stores socket path in `I3_SOCKET_PATH` property
+/// of root window, which is printed by `i3 --get-socketpath`.
+fn i3_socket_path() -> Option<PathBuf> {
+    env::var_os("DISPLAY")?;
+    let output = Command::new("i3").arg("--get-socketpath").stderr(Stdio::null()).output().ok().
+        filter(|output| output.status.success())?;
+    let path = String::from_utf8(output.stdout).ok()?;
+    Some(PathBuf::from(path.trim())).filter(|path| !path.as_os_str().is_empty())
+}
+
+fn send_message(stream: &mut impl Write, message_type: u32, payload: &[u8]) -> io::Result<()> {
+    let mut message = MAGIC.to_vec();
+    message.extend_from_slice(&(payload.len() as u32).to_ne_bytes());
+    message.extend_from_slice(&message_type.to_ne_bytes());
+    message.extend_from_slice(payload);
+    stream.write_all(&message)
+}
+
+fn read_message(stream: &mut impl Read) -> io::Result<(u32, Vec<u8>)> {
+    let mut header = [0u8; 14];
+    stream.read_exact(&mut header)?;
+    if &header[0..6] != MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid i3 IPC message"));
+    }
+    let len = u32::from_ne_bytes([header[6], header[7], header[8], header[9]]);
+    let message_type = u32::from_ne_bytes([header[10], header[11], header[12], header[13]]);
+    let mut payload = vec![0u8; len as usize];
+    stream.read_exact(&mut payload)?;
+    Ok((message_type, payload))
+}
+
+/// Returns function finding pid of X11 window, i3 reports only window id.
+#[cfg(feature = "x11")]
+fn window_pid_lookup() -> impl Fn(u32) -> Option<u32> {
+    // Only X11 windows can lack pid, so X11 connection is used just for them
+    let pids = env::var_os("DISPLAY").and_then(|_| crate::x11::WindowPids::connect());
+    move |win| pids.as_ref().and_then(|pids| pids.pid(win))
+}
+
+#[cfg(not(feature = "x11"))]
+fn window_pid_lookup() -> impl Fn(u32) -> Option<u32> {
+    |_| None
+}
+
+/// Subscribes to window events and reports currently focused window.
+fn connect(path: &Path, sender: &Sender<StateChanges>, window_pid: impl Fn(u32) -> Option<u32>) -> io::Result<UnixStream> {
+    let mut stream = UnixStream::connect(path)?;
+    send_message(&mut stream, SUBSCRIBE, br#"["window"]"#)?;
+    read_message(&mut stream)?;
+
+    let mut tree_stream = UnixStream::connect(path)?;
+    send_message(&mut tree_stream, GET_TREE, b"")?;
+    let (_, tree) = read_message(&mut tree_stream)?;
+    if let Some(node) = serde_json::from_slice::<Node>(&tree).ok().as_ref().and_then(|t| t.find_focused()) {
+        let _ = sender.send(node.focus_changed(window_pid));
+    }
+    Ok(stream)
+}
+
+/// Tracks focused window through sway/i3 IPC `window` events.
+pub(crate) struct SwayHandler {
+    path: PathBuf,
+}
+
+impl SwayHandler {
+    /// Returns handler when sway or i3 session is running.
+    pub fn detect() -> Option<SwayHandler> {
+        socket_path().map(|path| SwayHandler { path })
+    }
+}
+
+impl FocusSource for SwayHandler {
+    fn name(&self) -> &'static str {
+        "sway"
+    }
+
+    fn spawn(&mut self, sender: Sender<StateChanges>) -> io::Result<()> {
+        let path = self.path.clone();
+        let window_pid = window_pid_lookup();
+        let mut stream = Some(connect(&path, &sender, &window_pid)?);
+        let listener_sender = sender.clone();
+        let _x = supervise("sway", sender, move || {
+            let stream = match stream.take() {
+                Some(stream) => stream,
+                None => connect(&path, &listener_sender, &window_pid)?,
+            };
+            sway_listener(stream, &listener_sender, &window_pid)
+        })?;
+        Ok(())
+    }
+}
+
+/// Reports focus changes until IPC connection is lost.
+fn sway_listener(mut stream: UnixStream, sender: &Sender<StateChanges>, window_pid: impl Fn(u32) -> Option<u32>)
+                 -> io::Result<()> {
+    loop {
+        match read_message(&mut stream) {
+            Ok((WINDOW_EVENT, payload)) => {
+                match serde_json::from_slice::<WindowEvent>(&payload) {
+                    Ok(event) if event.change == "focus" || (event.change == "title" && event.container.focused) => {
+                        let change = event.container.focus_changed(&window_pid);
+                        debug!("App switch: {:?}", change);
+                        let _ = sender.send(change);
+                    }
+                    Ok(_) => {}
+                    Err(err) => {
+                        debug!("Can't parse window event: {:?}", err);
+                    }
+                }
+            }
+            Ok(_) => {}
+            Err(err) => return Err(io::Error::new(err.kind(), format!("IPC connection lost: {}", err))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(target_endian = "little")]
+    #[test]
+    fn message_framing() {
+        let mut message = Vec::new();
+        send_message(&mut message, SUBSCRIBE, br#"["window"]"#).unwrap();
+        assert_eq!(message, b"i3-ipc\x0a\x00\x00\x00\x02\x00\x00\x00[\"window\"]");
+
+        let mut reply: &[u8] = b"i3-ipc\x10\x00\x00\x00\x02\x00\x00\x00{\"success\":true}\
+                                 i3-ipc\x00\x00\x00\x00\x03\x00\x00\x80";
+        assert_eq!(read_message(&mut reply).unwrap(), (SUBSCRIBE, br#"{"success":true}"#.to_vec()));
+        assert_eq!(read_message(&mut reply).unwrap(), (WINDOW_EVENT, Vec::new()));
+        assert_eq!(read_message(&mut reply).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn invalid_magic_and_truncated_payload() {
+        let mut bad: &[u8] = b"i4-ipc\x00\x00\x00\x00\x00\x00\x00\x00";
+        assert_eq!(read_message(&mut bad).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        let mut message = Vec::new();
+        send_message(&mut message, GET_TREE, b"{}").unwrap();
+        let mut truncated = &message[..message.len() - 1];
+        assert_eq!(read_message(&mut truncated).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn focused_i3_window() {
+        let tree = br#"{"focused": false, "nodes": [{"focused": false, "nodes": [], "floating_nodes": [
+            {"focused": true, "name": "Inbox", "window": 4194307,
+             "window_properties": {"class": "thunderbird", "instance": "Mail"}, "nodes": []}]}]}"#;
+        let tree: Node = serde_json::from_slice(tree).unwrap();
+        let node = tree.find_focused().unwrap();
+        let change = node.focus_changed(|_| None);
+        assert!(matches!(change, StateChanges::FocusChanged { pid: 0, ref class, ref title, .. }
+                         if class == "thunderbird" && title == "Inbox"));
+        // Pid of i3 windows comes from X11 window
+        let change = node.focus_changed(|win| Some(win).filter(|win| *win == 4194307).map(|_| 1));
+        assert!(matches!(change, StateChanges::FocusChanged { pid: 1, .. }));
+    }
+
+    #[test]
+    fn sway_pid_is_preferred() {
+        let node: Node = serde_json::from_slice(br#"{"focused": true, "pid": 1, "window": 7, "app_id": null,
+            "window_properties": {"class": "Gimp"}}"#).unwrap();
+        let change = node.focus_changed(|_| panic!("X11 lookup for window with pid"));
+        assert!(matches!(change, StateChanges::FocusChanged { pid: 1, ref class, .. } if class == "Gimp"));
+    }
+}
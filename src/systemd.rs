@@ -0,0 +1,227 @@
+use std::env;
+use std::fs::{create_dir_all, write};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use directories::BaseDirs;
+
+use crate::control::Status;
+
+const UNIT_NAME: &str = "crown-controller.service";
+
+/// Sends state notifications to systemd through `NOTIFY_SOCKET` (`sd_notify` protocol),
+/// does nothing when not started by systemd.
+pub(crate) struct Notifier {
+    socket: Option<(UnixDatagram, SocketAddr)>,
+    watchdog_interval: Option<Duration>,
+    next_watchdog: Option<Instant>,
+    ready: bool,
+    status: String,
+}
+
+impl Notifier {
+    /// Reads notification socket and watchdog interval from environment and removes
+    /// them, so they aren't inherited by spawned commands.
+    pub fn from_env() -> Notifier {
+        let path = env::var_os("NOTIFY_SOCKET").map(PathBuf::from);
+        let watchdog_pid_matches = env::var("WATCHDOG_PID").ok().
+            and_then(|pid| pid.parse::<u32>().ok()).
+            is_none_or(|pid| pid == std::process::id());
+        let watchdog_interval = env::var("WATCHDOG_USEC").ok().
+            and_then(|usec| usec.parse::<u64>().ok()).
+            filter(|usec| *usec > 0 && watchdog_pid_matches).
+            map(|usec| Duration::from_micros(usec / 2));
+        for var in ["NOTIFY_SOCKET", "WATCHDOG_USEC", "WATCHDOG_PID"].iter() {
+            env::remove_var(var);
+        }
+
+        let socket = path.and_then(|path| {
+            let addr = match path.to_str().and_then(|p| p.strip_prefix('@')) {
+                Some(name) => SocketAddr::from_abstract_name(name),
+                None => SocketAddr::from_pathname(&path),
+            };
+            match (UnixDatagram::unbound(), addr) {
+                (Ok(socket), Ok(addr)) => Some((socket, addr)),
+                (Err(err), _) | (_, Err(err)) => {
+                    warn!("Can't use systemd notification socket {:?}: {}", path, err);
+                    None
+                }
+            }
+        });
+
+        Notifier {
+            next_watchdog: watchdog_interval.filter(|_| socket.is_some()).map(|i| Instant::now() + i),
+            socket,
+            watchdog_interval,
+            ready: false,
+            status: String::new(),
+        }
+    }
+
+    fn notify(&self, state: &str) {
+        if let Some((ref socket, ref addr)) = self.socket {
+            if let Err(err) = socket.send_to_addr(state.as_bytes(), addr) {
+                warn!("Can't notify systemd: {}", err);
+            }
+        }
+    }
+
+    /// Reports that daemon finished starting up.
+    pub fn ready(&mut self) {
+        if !self.ready {
+            self.ready = true;
+            self.notify("READY=1");
+        }
+    }
+
+    /// Updates status shown by `systemctl status`.
+    pub fn status(&mut self, status: String) {
+        if status != self.status {
+            self.notify(&format!("STATUS={}", status));
+            self.status = status;
+        }
+    }
+
+    pub fn next_watchdog(&self) -> Option<Instant> {
+        self.next_watchdog
+    }
+
+    /// Pings watchdog when it's due.
+    pub fn watchdog(&mut self) {
+        if let (Some(at), Some(interval)) = (self.next_watchdog, self.watchdog_interval) {
+            if at <= Instant::now() {
+                self.notify("WATCHDOG=1");
+                self.next_watchdog = Some(Instant::now() + interval);
+            }
+        }
+    }
+}
+
+/// Returns daemon state for `STATUS=` notification.
+pub(crate) fn status_line(status: &Status) -> String {
+    let failing: Vec<String> = status.health.iter().
+        filter(|(_, health)| !health.running).
+        map(|(backend, health)| format!("{} listener failed ({}), restarts: {}",
+                                        backend, health.last_error.as_deref().unwrap_or("unknown"), health.restarts)).
+        collect();
+    if !failing.is_empty() {
+        return failing.join("; ");
+    }
+    let mut line = if status.connected { "Keyboard connected" } else { "Keyboard disconnected" }.to_owned();
+    if let Some(battery) = status.battery {
+        line.push_str(&format!(", battery {}%", battery));
+    }
+    line.push_str(&format!(", profile {}", status.profile.as_deref().unwrap_or("global")));
+    line
+}
+
+/// Quotes `arg` for `ExecStart=`, `%` starts specifiers and `$` variables in unit files.
+fn exec_arg(arg: &str) -> String {
+    let arg = arg.replace('%', "%%").replace('$', "$$");
+    if arg.is_empty() || arg.contains(char::is_whitespace) || arg.contains('"') || arg.contains('\\') {
+        format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        arg
+    }
+}
+
+/// Writes systemd user unit starting this binary with `args`, returns process exit code.
+pub(crate) fn install_service(args: &[String]) -> i32 {
+    let exe = match env::current_exe() {
+        Ok(exe) => exe,
+        Err(err) => {
+            println!("Can't find path of crown-controller: {}", err);
+            return 1;
+        }
+    };
+    let dir = match BaseDirs::new() {
+        Some(dirs) => dirs.config_dir().join("systemd").join("user"),
+        None => {
+            println!("Can't find user configuration directory");
+            return 1;
+        }
+    };
+    let command: Vec<String> = Some(exe.to_string_lossy().as_ref()).into_iter().
+        chain(args.iter().map(String::as_str)).
+        map(exec_arg).
+        collect();
+    let unit = format!("[Unit]
+Description=Crown actions for Logitech Craft keyboard
+PartOf=graphical-session.target
+After=graphical-session.target
+
+[Service]
+Type=notify
+NotifyAccess=main
+TimeoutStartSec=infinity
+ExecStart={}
+Restart=on-failure
+RestartSec=5
+WatchdogSec=30
+
+[Install]
+WantedBy=graphical-session.target
+", command.join(" "));
+
+    let path = dir.join(UNIT_NAME);
+    if let Err(err) = create_dir_all(&dir).and_then(|_| write(&path, unit)) {
+        println!("Can't write {}: {}", path.display(), err);
+        return 1;
+    }
+    println!("Written {}, enable it with:
+    systemctl --user daemon-reload
+    systemctl --user enable --now {}", path.display(), UNIT_NAME);
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{Modifier, RatchetMode};
+    use crate::supervisor::Health;
+
+    use super::*;
+
+    fn status() -> Status {
+        Status {
+            connected: true,
+            health: Default::default(),
+            battery: Some(80),
+            app: Some("/usr/bin/gimp".to_owned()),
+            class: None,
+            profile: Some("gimp".to_owned()),
+            forced_profile: None,
+            ratchet_mode: RatchetMode::Ratcheted,
+            modifiers: Modifier::None,
+            layer: Modifier::None,
+            forced_layer: None,
+            log_filter: "info".to_owned(),
+        }
+    }
+
+    #[test]
+    fn status_lines() {
+        assert_eq!(status_line(&status()), "Keyboard connected, battery 80%, profile gimp");
+        let disconnected = Status { connected: false, battery: None, profile: None, ..status() };
+        assert_eq!(status_line(&disconnected), "Keyboard disconnected, profile global");
+        let mut failing = status();
+        let health = |running| Health { running, restarts: 2, last_error: Some("Crown device not found".to_owned()) };
+        failing.health.insert("x11".to_owned(), health(true));
+        assert_eq!(status_line(&failing), "Keyboard connected, battery 80%, profile gimp");
+        failing.health.insert("hid".to_owned(), health(false));
+        assert_eq!(status_line(&failing), "hid listener failed (Crown device not found), restarts: 2");
+    }
+
+    #[test]
+    fn exec_args_are_quoted() {
+        assert_eq!(exec_arg("--log"), "--log");
+        assert_eq!(exec_arg("info,hid=trace"), "info,hid=trace");
+        assert_eq!(exec_arg("100%"), "100%%");
+        assert_eq!(exec_arg("$HOME"), "$$HOME");
+        assert_eq!(exec_arg("/opt/my tools/plugin.py"), r#""/opt/my tools/plugin.py""#);
+        assert_eq!(exec_arg(r#"say "hi""#), r#""say \"hi\"""#);
+        assert_eq!(exec_arg(r"C:\dir"), r#""C:\\dir""#);
+        assert_eq!(exec_arg(""), r#""""#);
+    }
+}
@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::config::{Action, Modifier};
+
+struct PendingBatch {
+    command: String,
+    delta: i32,
+    deadline: Instant,
+}
+
+/// Collects rotation deltas for `Batch` operations until the crown stays idle
+/// for the configured time, so slow external tools are spawned only once.
+pub(crate) struct BatchQueue {
+    pending: Vec<PendingBatch>,
+}
+
+impl BatchQueue {
+    pub(crate) fn new() -> BatchQueue {
+        BatchQueue {
+            pending: Vec::new(),
+        }
+    }
+
+    pub(crate) fn add(&mut self, command: &str, idle: Duration, delta: i16) {
+        let deadline = Instant::now() + idle;
+        if let Some(batch) = self.pending.iter_mut().find(|b| b.command == command) {
+            batch.delta += delta as i32;
+            batch.deadline = deadline;
+        } else {
+            self.pending.push(PendingBatch {
+                command: command.to_owned(),
+                delta: delta as i32,
+                deadline,
+            });
+        }
+    }
+
+    pub(crate) fn next_deadline(&self) -> Option<Instant> {
+        self.pending.iter().map(|b| b.deadline).min()
+    }
+
+    /// Removes batches whose idle window has passed and returns their commands
+    /// with `{delta}` and `{abs_delta}` replaced by the accumulated rotation.
+    pub(crate) fn take_expired(&mut self) -> Vec<String> {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        self.pending.retain(|b| {
+            if b.deadline > now {
+                return true;
+            }
+            if b.delta != 0 {
+                expired.push(b.command.
+                    replace("{delta}", &b.delta.to_string()).
+                    replace("{abs_delta}", &b.delta.abs().to_string()));
+            }
+            false
+        });
+        expired
+    }
+}
+
+/// Remembers when actions were last executed to enforce per-action cooldowns.
+pub(crate) struct Cooldowns {
+    last_run: HashMap<(String, Modifier, Action), Instant>,
+}
+
+impl Cooldowns {
+    pub(crate) fn new() -> Cooldowns {
+        Cooldowns {
+            last_run: HashMap::new(),
+        }
+    }
+
+    /// Returns `true` and records the execution time if action of `profile` can run now.
+    pub(crate) fn try_run(&mut self, profile: &str, modifiers: Modifier, action: Action, cooldown: Duration) -> bool {
+        let now = Instant::now();
+        let key = (profile.to_owned(), modifiers, action);
+        if let Some(last) = self.last_run.get(&key) {
+            if now.duration_since(*last) < cooldown {
+                return false;
+            }
+        }
+        self.last_run.insert(key, now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::sleep;
+
+    use super::*;
+
+    #[test]
+    fn batch_expands_accumulated_delta() {
+        let mut queue = BatchQueue::new();
+        queue.add("zoom {delta} {abs_delta}", Duration::from_millis(0), -3);
+        queue.add("zoom {delta} {abs_delta}", Duration::from_millis(0), 1);
+        assert_eq!(queue.take_expired(), vec!["zoom -2 2"]);
+        assert!(queue.next_deadline().is_none());
+    }
+
+    #[test]
+    fn batch_waits_for_idle_time() {
+        let mut queue = BatchQueue::new();
+        queue.add("a {delta}", Duration::from_millis(50), 1);
+        let first = queue.next_deadline().unwrap();
+        assert!(queue.take_expired().is_empty());
+        queue.add("a {delta}", Duration::from_millis(50), 1);
+        assert!(queue.next_deadline().unwrap() >= first);
+        sleep(Duration::from_millis(60));
+        assert_eq!(queue.take_expired(), vec!["a 2"]);
+    }
+
+    #[test]
+    fn batches_keep_order_and_skip_zero_delta() {
+        let mut queue = BatchQueue::new();
+        queue.add("b {delta}", Duration::from_millis(0), 1);
+        queue.add("a {delta}", Duration::from_millis(0), 2);
+        queue.add("c {delta}", Duration::from_millis(0), 1);
+        queue.add("c {delta}", Duration::from_millis(0), -1);
+        assert_eq!(queue.take_expired(), vec!["b 1", "a 2"]);
+    }
+
+    #[test]
+    fn cooldown_is_per_profile_and_action() {
+        let mut cooldowns = Cooldowns::new();
+        let cooldown = Duration::from_millis(50);
+        assert!(cooldowns.try_run("global", Modifier::None, Action::Left, cooldown));
+        assert!(!cooldowns.try_run("global", Modifier::None, Action::Left, cooldown));
+        assert!(cooldowns.try_run("global", Modifier::None, Action::Right, cooldown));
+        assert!(cooldowns.try_run("global", Modifier::Shift, Action::Left, cooldown));
+        assert!(cooldowns.try_run("firefox", Modifier::None, Action::Left, cooldown));
+        sleep(Duration::from_millis(60));
+        assert!(cooldowns.try_run("global", Modifier::None, Action::Left, cooldown));
+    }
+}
@@ -0,0 +1,401 @@
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{self, ErrorKind, Write};
+use std::os::unix::io::{AsFd, AsRawFd, FromRawFd};
+use std::sync::Arc;
+use std::time::Instant;
+
+use crossbeam_channel::{Receiver, Sender};
+use mio::{Events, Interest, Poll, Token, Waker};
+use mio::unix::SourceFd;
+use wayland_client::{Connection, delegate_noop, Dispatch, event_created_child, EventQueue, Proxy, QueueHandle};
+use wayland_client::backend::{ObjectId, WaylandError};
+use wayland_client::protocol::wl_pointer::{Axis, AxisSource};
+use wayland_client::protocol::wl_registry::{self, WlRegistry};
+use wayland_client::protocol::wl_seat::WlSeat;
+use wayland_protocols_misc::zwp_virtual_keyboard_v1::client::zwp_virtual_keyboard_manager_v1::ZwpVirtualKeyboardManagerV1;
+use wayland_protocols_misc::zwp_virtual_keyboard_v1::client::zwp_virtual_keyboard_v1::ZwpVirtualKeyboardV1;
+use wayland_protocols_wlr::foreign_toplevel::v1::client::zwlr_foreign_toplevel_handle_v1::{self, ZwlrForeignToplevelHandleV1};
+use wayland_protocols_wlr::foreign_toplevel::v1::client::zwlr_foreign_toplevel_manager_v1::{self, ZwlrForeignToplevelManagerV1};
+use wayland_protocols_wlr::virtual_pointer::v1::client::zwlr_virtual_pointer_manager_v1::ZwlrVirtualPointerManagerV1;
+use wayland_protocols_wlr::virtual_pointer::v1::client::zwlr_virtual_pointer_v1::ZwlrVirtualPointerV1;
+
+use crate::backend::ActionSink;
+use crate::supervisor::supervise;
+use crate::StateChanges;
+
+const KEYMAP_FORMAT_XKB_V1: u32 = 1;
+const FIRST_KEYCODE: u32 = 12;
+const SCROLL_STEP: f64 = 15.0;
+
+pub(crate) struct WaylandHandler {
+    my_sender: Sender<WaylandCommands>,
+    waker: Arc<Waker>,
+}
+
+impl WaylandHandler {
+    /// Connects to wlroots based compositor, keys are sent through virtual keyboard and
+    /// when `track_focus` is set active window is tracked through foreign toplevel protocol.
+    pub fn new(event_receiver: Sender<StateChanges>, track_focus: bool) -> io::Result<WaylandHandler> {
+        let mut connection = Some(connect(event_receiver.clone(), track_focus)?);
+        let (my_sender, my_receiver) = crossbeam_channel::unbounded();
+        let mut poll = Poll::new()?;
+        let waker = Arc::new(Waker::new(poll.registry(), Token(10))?);
+
+        let sender = event_receiver.clone();
+        let _x = supervise("wayland", event_receiver, move || {
+            // Compositor state is queried again when listener is restarted
+            let (conn, queue, state) = match connection.take() {
+                Some(connection) => connection,
+                None => connect(sender.clone(), track_focus)?,
+            };
+            wayland_listener(conn, queue, state, &my_receiver, &mut poll)
+        })?;
+
+        Ok(WaylandHandler {
+            my_sender,
+            waker,
+        })
+    }
+}
+
+impl ActionSink for WaylandHandler {
+    fn name(&self) -> &'static str {
+        "wayland"
+    }
+
+    fn send_key(&self, keysym: u32, modifiers: u8) {
+        if self.my_sender.send(WaylandCommands::SendKey { keysym, modifiers }).is_ok() {
+            let _ = self.waker.wake();
+        }
+    }
+
+    fn scroll(&self, amount: i32) {
+        if self.my_sender.send(WaylandCommands::Scroll { amount }).is_ok() {
+            let _ = self.waker.wake();
+        }
+    }
+}
+
+pub(crate) enum WaylandCommands {
+    SendKey { keysym: u32, modifiers: u8 },
+    Scroll { amount: i32 },
+}
+
+#[derive(Default)]
+struct Toplevel {
+    app_id: String,
+    title: String,
+    activated: bool,
+}
+
+struct WaylandState {
+    sender: Sender<StateChanges>,
+    seat: Option<WlSeat>,
+    keyboard_manager: Option<ZwpVirtualKeyboardManagerV1>,
+    pointer_manager: Option<ZwlrVirtualPointerManagerV1>,
+    toplevel_manager: Option<ZwlrForeignToplevelManagerV1>,
+    toplevels: HashMap<ObjectId, Toplevel>,
+    active: Option<(ObjectId, String)>,
+    track_focus: bool,
+}
+
+impl Dispatch<WlRegistry, ()> for WaylandState {
+    fn event(state: &mut Self, registry: &WlRegistry, event: wl_registry::Event, _: &(),
+             _: &Connection, qh: &QueueHandle<Self>) {
+        if let wl_registry::Event::Global { name, interface, version } = event {
+            match interface.as_str() {
+                "wl_seat" if state.seat.is_none() => {
+                    state.seat = Some(registry.bind(name, version.min(7), qh, ()));
+                }
+                "zwp_virtual_keyboard_manager_v1" => {
+                    state.keyboard_manager = Some(registry.bind(name, 1, qh, ()));
+                }
+                "zwlr_virtual_pointer_manager_v1" => {
+                    state.pointer_manager = Some(registry.bind(name, version.min(2), qh, ()));
+                }
+                "zwlr_foreign_toplevel_manager_v1" if state.track_focus => {
+                    state.toplevel_manager = Some(registry.bind(name, version.min(3), qh, ()));
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+impl Dispatch<ZwlrForeignToplevelManagerV1, ()> for WaylandState {
+    fn event(_: &mut Self, _: &ZwlrForeignToplevelManagerV1, _: zwlr_foreign_toplevel_manager_v1::Event, _: &(),
+             _: &Connection, _: &QueueHandle<Self>) {}
+
+    event_created_child!(WaylandState, ZwlrForeignToplevelManagerV1, [
+        zwlr_foreign_toplevel_manager_v1::EVT_TOPLEVEL_OPCODE => (ZwlrForeignToplevelHandleV1, ()),
+    ]);
+}
+
+impl Dispatch<ZwlrForeignToplevelHandleV1, ()> for WaylandState {
+    fn event(state: &mut Self, handle: &ZwlrForeignToplevelHandleV1, event: zwlr_foreign_toplevel_handle_v1::Event,
+             _: &(), _: &Connection, _: &QueueHandle<Self>) {
+        use zwlr_foreign_toplevel_handle_v1::Event;
+
+        let id = handle.id();
+        match event {
+            Event::Title { title } => {
+                state.toplevels.entry(id).or_default().title = title;
+            }
+            Event::AppId { app_id } => {
+                state.toplevels.entry(id).or_default().app_id = app_id;
+            }
+            Event::State { state: states } => {
+                let activated = zwlr_foreign_toplevel_handle_v1::State::Activated as u32;
+                state.toplevels.entry(id).or_default().activated = states.chunks_exact(4).
+                    any(|s| u32::from_ne_bytes([s[0], s[1], s[2], s[3]]) == activated);
+            }
+            Event::Done => {
+                if let Some(toplevel) = state.toplevels.get(&id).filter(|t| t.activated) {
+                    let active = Some((id, toplevel.title.clone()));
+                    if state.active != active {
+                        state.active = active;
+                        let change = StateChanges::FocusChanged {
+                            pid: 0,
+                            program: "".to_owned(),
+                            sandbox_id: None,
+                            cmdline: Vec::new(),
+                            class: toplevel.app_id.clone(),
+                            title: toplevel.title.clone(),
+                        };
+                        debug!("App switch: {:?}", change);
+                        let _ = state.sender.send(change);
+                    }
+                }
+            }
+            Event::Closed => {
+                state.toplevels.remove(&id);
+                handle.destroy();
+            }
+            _ => {}
+        }
+    }
+}
+
+delegate_noop!(WaylandState: ignore WlSeat);
+delegate_noop!(WaylandState: ZwpVirtualKeyboardManagerV1);
+delegate_noop!(WaylandState: ZwpVirtualKeyboardV1);
+delegate_noop!(WaylandState: ZwlrVirtualPointerManagerV1);
+delegate_noop!(WaylandState: ZwlrVirtualPointerV1);
+
+/// Virtual keyboard with keymap built from keysyms that were sent, keymap is
+/// extended and uploaded again when a new keysym is used.
+/// Keysyms assigned to keycodes of the virtual keyboard, Wayland has no way to send
+/// keysym directly so the keymap is extended with each new keysym.
+struct KeysymMap {
+    keysyms: Vec<u32>,
+}
+
+impl KeysymMap {
+    fn keymap(&self) -> String {
+        let mut keycodes = String::new();
+        let mut symbols = String::new();
+        for (idx, keysym) in self.keysyms.iter().enumerate() {
+            let keycode = FIRST_KEYCODE as usize + idx;
+            keycodes.push_str(&format!("<K{}> = {};\n", keycode, keycode));
+            symbols.push_str(&format!("key <K{}> {{ [ 0x{:x} ] }};\n", keycode, keysym));
+        }
+        format!("xkb_keymap {{
+xkb_keycodes \"crown\" {{
+minimum = 8;
+maximum = 255;
+<LFSH> = 9;
+<LCTL> = 10;
+<LALT> = 11;
+{}}};
+xkb_types \"crown\" {{ include \"complete\" }};
+xkb_compatibility \"crown\" {{ include \"complete\" }};
+xkb_symbols \"crown\" {{
+key <LFSH> {{ [ Shift_L ] }};
+key <LCTL> {{ [ Control_L ] }};
+key <LALT> {{ [ Alt_L ] }};
+modifier_map Shift {{ <LFSH> }};
+modifier_map Control {{ <LCTL> }};
+modifier_map Mod1 {{ <LALT> }};
+{}}};
+}};
+", keycodes, symbols)
+    }
+
+    /// Returns evdev keycode for keysym, keycodes are offset by 8 from XKB ones, and
+    /// `true` when keysym was added so the keymap has to be uploaded again.
+    fn keycode(&mut self, keysym: u32) -> (u32, bool) {
+        if let Some(idx) = self.keysyms.iter().position(|k| *k == keysym) {
+            return (FIRST_KEYCODE + idx as u32 - 8, false);
+        }
+        if FIRST_KEYCODE as usize + self.keysyms.len() > 255 {
+            self.keysyms.clear();
+        }
+        self.keysyms.push(keysym);
+        (FIRST_KEYCODE + self.keysyms.len() as u32 - 1 - 8, true)
+    }
+}
+
+struct VirtualKeyboard {
+    keyboard: ZwpVirtualKeyboardV1,
+    keysyms: KeysymMap,
+}
+
+impl VirtualKeyboard {
+    fn upload_keymap(&self) -> io::Result<()> {
+        let mut keymap = self.keysyms.keymap().into_bytes();
+        keymap.push(0);
+        let name = CString::new("crown-keymap").unwrap();
+        let fd = unsafe { libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut file = unsafe { File::from_raw_fd(fd) };
+        file.write_all(&keymap)?;
+        self.keyboard.keymap(KEYMAP_FORMAT_XKB_V1, file.as_fd(), keymap.len() as u32);
+        Ok(())
+    }
+
+    fn keycode(&mut self, keysym: u32) -> Option<u32> {
+        let (keycode, changed) = self.keysyms.keycode(keysym);
+        if changed {
+            self.upload_keymap().ok()?;
+        }
+        Some(keycode)
+    }
+
+    fn send_key(&mut self, keysym: u32, modifiers: u8, time: u32) {
+        if let Some(keycode) = self.keycode(keysym) {
+            self.keyboard.modifiers(modifiers as u32, 0, 0, 0);
+            self.keyboard.key(time, keycode, 1);
+            self.keyboard.key(time, keycode, 0);
+            self.keyboard.modifiers(0, 0, 0, 0);
+        }
+    }
+}
+
+fn unsupported() -> io::Error {
+    io::Error::new(ErrorKind::Unsupported, "Compositor doesn't support virtual keyboard protocol")
+}
+
+fn connect(sender: Sender<StateChanges>, track_focus: bool)
+           -> io::Result<(Connection, EventQueue<WaylandState>, WaylandState)> {
+    let conn = Connection::connect_to_env().map_err(io::Error::other)?;
+    let mut queue = conn.new_event_queue();
+    let _registry = conn.display().get_registry(&queue.handle(), ());
+    let mut state = WaylandState {
+        sender,
+        seat: None,
+        keyboard_manager: None,
+        pointer_manager: None,
+        toplevel_manager: None,
+        toplevels: HashMap::new(),
+        active: None,
+        track_focus,
+    };
+    queue.roundtrip(&mut state).map_err(io::Error::other)?;
+    if state.keyboard_manager.is_none() || state.seat.is_none() {
+        return Err(unsupported());
+    }
+    Ok((conn, queue, state))
+}
+
+/// Sends keys and scroll events and reports focus changes until connection to compositor is lost.
+fn wayland_listener(conn: Connection, mut queue: EventQueue<WaylandState>, mut state: WaylandState,
+                    receiver: &Receiver<WaylandCommands>, poll: &mut Poll) -> io::Result<()> {
+    let qh = queue.handle();
+    let start = Instant::now();
+    let seat = state.seat.clone().ok_or_else(unsupported)?;
+    let mut keyboard = state.keyboard_manager.as_ref().map(|m| VirtualKeyboard {
+        keyboard: m.create_virtual_keyboard(&seat, &qh, ()),
+        keysyms: KeysymMap { keysyms: Vec::new() },
+    }).ok_or_else(unsupported)?;
+    let pointer = state.pointer_manager.as_ref().map(|m| m.create_virtual_pointer(Some(&seat), &qh, ()));
+    keyboard.upload_keymap().map_err(|e| io::Error::new(e.kind(), format!("Can't upload keymap: {}", e)))?;
+
+    let wayland_token = Token(0);
+    let mut events = Events::with_capacity(2);
+    let fd = conn.backend().poll_fd().as_raw_fd();
+    poll.registry().register(&mut SourceFd(&fd), wayland_token, Interest::READABLE)?;
+    let registry = poll.registry().try_clone()?;
+    let lost = |err: &dyn std::fmt::Display| {
+        let _ = registry.deregister(&mut SourceFd(&fd));
+        io::Error::other(format!("Connection lost: {}", err))
+    };
+
+    loop {
+        if let Err(err) = queue.dispatch_pending(&mut state) {
+            return Err(lost(&err));
+        }
+        let _ = queue.flush();
+        if let Some(guard) = queue.prepare_read() {
+            if let Err(err) = poll.poll(&mut events, None) {
+                if err.kind() != ErrorKind::Interrupted {
+                    return Err(err);
+                }
+            }
+            if events.iter().any(|e| e.token() == wayland_token) {
+                match guard.read() {
+                    Err(WaylandError::Io(err)) if err.kind() == ErrorKind::WouldBlock => {}
+                    Err(err) => return Err(lost(&err)),
+                    Ok(_) => {}
+                }
+            }
+        }
+        while let Ok(command) = receiver.try_recv() {
+            let time = start.elapsed().as_millis() as u32;
+            match command {
+                WaylandCommands::SendKey { keysym, modifiers } => {
+                    debug!("command {:x?} {:x?}", keysym, modifiers);
+                    keyboard.send_key(keysym, modifiers, time);
+                }
+                WaylandCommands::Scroll { amount } => {
+                    if let Some(pointer) = pointer.as_ref() {
+                        pointer.axis_source(AxisSource::Wheel);
+                        pointer.axis_discrete(time, Axis::VerticalScroll, SCROLL_STEP * amount as f64, amount);
+                        pointer.frame();
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keysyms_get_stable_keycodes() {
+        let mut keysyms = KeysymMap { keysyms: Vec::new() };
+        // XKB keycode 12 is evdev keycode 4
+        assert_eq!(keysyms.keycode(0xff51), (4, true));
+        assert_eq!(keysyms.keycode(0x1008ff13), (5, true));
+        assert_eq!(keysyms.keycode(0xff51), (4, false));
+
+        let keymap = keysyms.keymap();
+        assert!(keymap.contains("<K12> = 12;\n<K13> = 13;\n"));
+        assert!(keymap.contains("key <K12> { [ 0xff51 ] };\nkey <K13> { [ 0x1008ff13 ] };\n"));
+    }
+
+    #[test]
+    fn full_keymap_starts_over() {
+        let mut keysyms = KeysymMap { keysyms: (0..244).collect() };
+        assert_eq!(keysyms.keycode(243), (255 - 8, false));
+        assert_eq!(keysyms.keycode(1000), (4, true));
+        assert_eq!(keysyms.keysyms, vec![1000]);
+    }
+
+    #[test]
+    fn modifier_masks_match_keymap() {
+        let keymap = KeysymMap { keysyms: Vec::new() }.keymap();
+        // Masks from config are core X11 modifier bits: Shift, Control and Mod1
+        let operation: crate::config::Operation = serde_yaml::from_str("KeyPress: Shift+Ctrl+Alt+a").unwrap();
+        assert!(matches!(operation, crate::config::Operation::KeyPress(_, modifiers) if modifiers == 0x1 | 0x4 | 0x8));
+        for line in &["modifier_map Shift { <LFSH> };", "modifier_map Control { <LCTL> };",
+                      "modifier_map Mod1 { <LALT> };", "key <LFSH> { [ Shift_L ] };"] {
+            assert!(keymap.contains(line), "{} missing", line);
+        }
+    }
+}
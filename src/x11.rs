@@ -1,8 +1,7 @@
 use std::collections::HashMap;
-use std::fs::read_link;
+use std::io::{self, ErrorKind};
 use std::os::unix::io::AsRawFd;
 use std::sync::Arc;
-use std::thread::spawn;
 
 use crossbeam_channel::{Receiver, Sender};
 use mio::{Events, Interest, Poll, Token, Waker};
@@ -10,19 +9,26 @@ use mio::unix::SourceFd;
 use x11rb::{atom_manager, CURRENT_TIME, NONE};
 use x11rb::connection::Connection;
 use x11rb::protocol::Event;
+use x11rb::protocol::res::{self, ClientIdMask, ClientIdSpec, query_client_ids};
+use x11rb::protocol::xkb::{self, EventType, ID, KeySymMap, KeyType, MapPart, SelectEventsAux, StatePart};
 use x11rb::protocol::xproto::{AtomEnum, change_window_attributes, ChangeWindowAttributesAux, EventMask,
                               get_keyboard_mapping, get_modifier_mapping, get_property,
-                              KEY_PRESS_EVENT, KEY_RELEASE_EVENT,
+                              BUTTON_PRESS_EVENT, BUTTON_RELEASE_EVENT, KEY_PRESS_EVENT, KEY_RELEASE_EVENT,
                               query_keymap};
 use x11rb::protocol::xtest::fake_input;
 use x11rb::rust_connection::RustConnection;
 
+use crate::backend::ActionSink;
 use super::StateChanges;
+use crate::process::{command_line, program_path, sandbox_id, translate_pid};
+use crate::supervisor::supervise;
 
 atom_manager! {
     pub AtomCollection: AtomCollectionCookie {
         _NET_WM_PID,
+        _NET_WM_NAME,
         _NET_ACTIVE_WINDOW,
+        UTF8_STRING,
     }
 }
 pub(crate) struct X11Handler {
@@ -31,41 +37,114 @@ pub(crate) struct X11Handler {
 }
 
 impl X11Handler {
-    pub fn new(event_receiver: Sender<StateChanges>, debug_enabled: bool) -> std::io::Result<X11Handler> {
+    /// Creates handler sending keys through XTest, when `track_focus` is set it also
+    /// reports changes of active window.
+    pub fn new(event_receiver: Sender<StateChanges>, track_focus: bool) -> io::Result<X11Handler> {
+        let mut connection = Some(RustConnection::connect(None).map_err(io::Error::other)?);
         let (my_sender, my_receiver) = crossbeam_channel::unbounded();
-        let poll = Poll::new()?;
+        let mut poll = Poll::new()?;
         let waker = Arc::new(Waker::new(poll.registry(), Token(10))?);
 
-        let _x = spawn(move || x11_listener(event_receiver, my_receiver, poll, debug_enabled));
+        let sender = event_receiver.clone();
+        let _x = supervise("x11", event_receiver, move || {
+            // Connection is opened again when listener is restarted, e.g. after X server restart
+            let (conn, screen_num) = match connection.take() {
+                Some(connection) => connection,
+                None => RustConnection::connect(None).map_err(io::Error::other)?,
+            };
+            x11_listener(conn, screen_num, &sender, &my_receiver, &mut poll, track_focus)
+        })?;
 
         Ok(X11Handler {
             my_sender,
             waker,
         })
     }
+}
+
+impl ActionSink for X11Handler {
+    fn name(&self) -> &'static str {
+        "x11"
+    }
 
-    pub fn send_key(&self, keysym: u32, modifiers: u8) {
+    fn send_key(&self, keysym: u32, modifiers: u8) {
         if self.my_sender.send(X11Commands::SendKey { keysym, modifiers }).is_ok() {
             let _ = self.waker.wake();
         }
     }
+
+    fn scroll(&self, amount: i32) {
+        if self.my_sender.send(X11Commands::Scroll { amount }).is_ok() {
+            let _ = self.waker.wake();
+        }
+    }
 }
 
 pub(crate) enum X11Commands {
-    SendKey { keysym: u32, modifiers: u8 }
+    SendKey { keysym: u32, modifiers: u8 },
+    Scroll { amount: i32 },
+}
+
+/// Keycode and modifiers needed for producing keysym.
+type KeysymMapping = HashMap<u32, (u8, u8)>;
+
+const SHIFT_MASK: u8 = 1;
+const LOCK_MASK: u8 = 2;
+const MODE_SWITCH: u32 = 0xff7e;
+const ISO_LEVEL3_SHIFT: u32 = 0xfe03;
+
+/// Returns modifiers selecting `column` of core keyboard mapping: columns 0-1 are group 1,
+/// 2-3 group 2 selected by `Mode_switch` and 4-5 level 3 selected by `ISO_Level3_Shift`,
+/// odd columns need Shift. `None` when column can't be selected.
+fn column_modifiers(column: usize, mode_switch: Option<u8>, level3: Option<u8>) -> Option<u8> {
+    let shift = if column % 2 == 1 { SHIFT_MASK } else { 0 };
+    match column / 2 {
+        0 => Some(shift),
+        1 => mode_switch.map(|modifier| modifier | shift),
+        2 => level3.map(|modifier| modifier | shift),
+        _ => None,
+    }
 }
 
-fn keysym_to_keycode_mapping(conn: &impl Connection) -> (HashMap<u32, (u8, u8)>, Vec<(u8, u8)>) {
+/// Maps keysyms from `keysyms` (`per_keycode` columns for each keycode starting from
+/// `min_keycode`) to keycode and modifiers, keysyms in lower columns are preferred.
+fn core_mapping(keysyms: &[u32], per_keycode: usize, min_keycode: u8, keycodes_of_mods: &[(u8, u8)]) -> KeysymMapping {
+    let keys: Vec<&[u32]> = keysyms.chunks(per_keycode.max(1)).collect();
+    let modifier_of = |keysym: u32| keycodes_of_mods.iter().
+        find(|(keycode, _)| keycode.checked_sub(min_keycode).
+            and_then(|index| keys.get(index as usize)).
+            is_some_and(|syms| syms.contains(&keysym))).
+        map(|(_, modifier)| *modifier);
+    let (mode_switch, level3) = (modifier_of(MODE_SWITCH), modifier_of(ISO_LEVEL3_SHIFT));
+
+    let mut mapping = KeysymMapping::new();
+    for column in 0..per_keycode {
+        let modifiers = match column_modifiers(column, mode_switch, level3) {
+            Some(modifiers) => modifiers,
+            None => continue,
+        };
+        for (index, syms) in keys.iter().enumerate() {
+            if let Some(keysym) = syms.get(column).filter(|keysym| **keysym != 0) {
+                mapping.entry(*keysym).or_insert((min_keycode + index as u8, modifiers));
+            }
+        }
+    }
+    mapping
+}
+
+fn core_keysym_mapping(conn: &impl Connection, keycodes_of_mods: &[(u8, u8)]) -> KeysymMapping {
     let setup = conn.setup();
-    let reply = get_keyboard_mapping(conn, setup.min_keycode, setup.max_keycode - setup.min_keycode).
-        unwrap().reply().unwrap();
-    let mapping = reply.keysyms.chunks(reply.keysyms_per_keycode as usize)
-        .enumerate().flat_map(|(index, keysyms)| {
-        let keycode = index as u8 + setup.min_keycode;
-        keysyms.iter().enumerate().map(move |(idx, keysym)| (*keysym, (keycode, idx as u8)))
-    }).collect();
-
-    let keycodes_of_mods = get_modifier_mapping(conn).
+    match get_keyboard_mapping(conn, setup.min_keycode, setup.max_keycode - setup.min_keycode + 1).
+        ok().and_then(|c| c.reply().ok())
+    {
+        Some(reply) => core_mapping(&reply.keysyms, reply.keysyms_per_keycode as usize, setup.min_keycode,
+                                    keycodes_of_mods),
+        None => KeysymMapping::new(),
+    }
+}
+
+fn modifier_keycodes(conn: &impl Connection) -> Vec<(u8, u8)> {
+    get_modifier_mapping(conn).
         map_or_else(|_| Vec::new(),
                     |c| c.reply().
                         map_or_else(|_| Vec::new(),
@@ -79,62 +158,275 @@ fn keysym_to_keycode_mapping(conn: &impl Connection) -> (HashMap<u32, (u8, u8)>,
                                             }
                                         }
                                         keycodes_of_mods
-                                    }));
-    (mapping, keycodes_of_mods)
+                                    }))
 }
 
-fn send_keypress(conn: &impl Connection, keycode: u8, modifiers: u8, keycodes_of_mods: &[(u8, u8)]) -> () {
-    let mods_to_restore = query_keymap(conn).
-        map_or_else(|_| Vec::new(),
-                    |c| c.reply().
-                        map_or_else(|_| Vec::new(),
-                                    |r| {
-                                        let mut to_restore = Vec::new();
-                                        let mut pressed_modifiers = 0u8;
-                                        for (mod_keycode, modifier) in keycodes_of_mods {
-                                            if r.keys[(*mod_keycode / 8) as usize] & (1 << (*mod_keycode & 7)) != 0 {
-                                                let _ = fake_input(conn, KEY_RELEASE_EVENT, *mod_keycode, CURRENT_TIME, NONE, 0, 0, 0);
-                                                let _ = conn.flush();
-                                                if *modifier & modifiers == 0 {
-                                                    to_restore.push((*mod_keycode, KEY_PRESS_EVENT));
-                                                }
-                                                pressed_modifiers = pressed_modifiers | *modifier;
-                                            }
-                                        }
-                                        let mut modifiers_to_press = modifiers & !pressed_modifiers;
-                                        for (mod_keycode, modifier) in keycodes_of_mods {
-                                            if *modifier & modifiers_to_press != 0 {
-                                                let _ = fake_input(conn, KEY_PRESS_EVENT, *mod_keycode, CURRENT_TIME, NONE, 0, 0, 0);
-                                                let _ = conn.flush();
-                                                to_restore.push((*mod_keycode, KEY_RELEASE_EVENT));
-                                                modifiers_to_press = modifiers_to_press & !*modifier;
-                                            }
-                                        }
-                                        to_restore
-                                    },
-                        ));
-    let _ = fake_input(conn, KEY_PRESS_EVENT, keycode, CURRENT_TIME, NONE, 0, 0, 0);
-    let _ = conn.flush();
-    let _ = fake_input(conn, KEY_RELEASE_EVENT, keycode, CURRENT_TIME, NONE, 0, 0, 0);
-    let _ = conn.flush();
-    for (mod_keycode, action) in mods_to_restore {
-        let _ = fake_input(conn, action, mod_keycode, CURRENT_TIME, NONE, 0, 0, 0);
+/// Selects XKB events notifying about keyboard mapping and group changes,
+/// returns `false` when server doesn't support XKB.
+fn enable_xkb(conn: &impl Connection) -> bool {
+    let supported = xkb::use_extension(conn, 1, 0).ok().
+        and_then(|c| c.reply().ok()).
+        is_some_and(|r| r.supported);
+    if supported {
+        let events = u16::from(EventType::NewKeyboardNotify) | u16::from(EventType::MapNotify) |
+            u16::from(EventType::StateNotify);
+        let map_parts = u16::from(MapPart::KeyTypes) | u16::from(MapPart::KeySyms) | u16::from(MapPart::ModifierMap);
+        let _ = xkb::select_events(conn, ID::UseCoreKbd.into(), 0u16, events, map_parts, map_parts,
+                                   &SelectEventsAux::new());
+        let _ = conn.flush();
+    }
+    supported
+}
+
+/// Maps group to one of groups defined for the key, following key's rules for out of range groups.
+fn effective_group(group_info: u8, group: u8) -> Option<u8> {
+    let num_groups = group_info & 0x0f;
+    if num_groups == 0 {
+        None
+    } else if group < num_groups {
+        Some(group)
+    } else {
+        Some(match group_info & 0xc0 {
+            0x40 => num_groups - 1,
+            0x80 if (group_info >> 4) & 0x03 < num_groups => (group_info >> 4) & 0x03,
+            0x80 => 0,
+            _ => group % num_groups,
+        })
+    }
+}
+
+/// Keyboard layout of the core keyboard, keysyms are mapped to keycodes
+/// from the active XKB group.
+struct Keymap {
+    xkb_enabled: bool,
+    first_keycode: u8,
+    types: Vec<KeyType>,
+    syms: Vec<KeySymMap>,
+    group: u8,
+    mapping: KeysymMapping,
+    keycodes_of_mods: Vec<(u8, u8)>,
+}
+
+impl Keymap {
+    fn new(conn: &impl Connection, xkb_enabled: bool) -> Keymap {
+        let mut keymap = Keymap {
+            xkb_enabled,
+            first_keycode: 0,
+            types: Vec::new(),
+            syms: Vec::new(),
+            group: 0,
+            mapping: HashMap::new(),
+            keycodes_of_mods: Vec::new(),
+        };
+        keymap.reload(conn);
+        keymap
+    }
+
+    /// Fetches key types, keysyms, active group and modifier keys from the server.
+    fn reload(&mut self, conn: &impl Connection) {
+        self.keycodes_of_mods = modifier_keycodes(conn);
+        if !self.xkb_enabled {
+            self.mapping = core_keysym_mapping(conn, &self.keycodes_of_mods);
+            return;
+        }
+        let setup = conn.setup();
+        let parts = u16::from(MapPart::KeyTypes) | u16::from(MapPart::KeySyms);
+        let map = xkb::get_map(conn, ID::UseCoreKbd.into(), parts, 0u16, 0, 0,
+                               setup.min_keycode, setup.max_keycode - setup.min_keycode + 1,
+                               0, 0, 0, 0, 0u16, 0, 0, 0, 0, 0, 0).
+            ok().and_then(|c| c.reply().ok());
+        if let Some(map) = map {
+            self.first_keycode = map.first_key_sym;
+            self.types = map.map.types_rtrn.unwrap_or_default();
+            self.syms = map.map.syms_rtrn.unwrap_or_default();
+        }
+        self.group = xkb::get_state(conn, ID::UseCoreKbd.into()).ok().
+            and_then(|c| c.reply().ok()).
+            map_or(0, |r| r.group.into());
+        self.rebuild();
+    }
+
+    fn set_group(&mut self, group: u8) {
+        if group != self.group {
+            self.group = group;
+            self.rebuild();
+        }
+    }
+
+    /// Returns modifiers selecting `level` of key type, `None` when level can
+    /// only be reached with Lock.
+    fn level_modifiers(&self, type_index: u8, level: u8) -> Option<u8> {
+        if level == 0 {
+            return Some(0);
+        }
+        self.types.get(type_index as usize)?.map.iter().
+            filter(|entry| entry.active && entry.level == level && entry.mods_mask & LOCK_MASK == 0).
+            map(|entry| entry.mods_mask).
+            min_by_key(|mask| mask.count_ones())
+    }
+
+    fn rebuild(&mut self) {
+        let mut mapping: KeysymMapping = HashMap::new();
+        let mut levels_of_keysyms: HashMap<u32, u8> = HashMap::new();
+        for (index, key) in self.syms.iter().enumerate() {
+            let keycode = self.first_keycode + index as u8;
+            if let Some(group) = effective_group(key.group_info, self.group) {
+                let width = key.width as usize;
+                let levels = key.syms.iter().skip(group as usize * width).take(width);
+                for (level, keysym) in levels.enumerate() {
+                    let level = level as u8;
+                    // Prefer keys producing keysym with the lowest level
+                    if *keysym == 0 || levels_of_keysyms.get(keysym).is_some_and(|l| *l <= level) {
+                        continue;
+                    }
+                    if let Some(modifiers) = self.level_modifiers(key.kt_index[group as usize], level) {
+                        mapping.insert(*keysym, (keycode, modifiers));
+                        levels_of_keysyms.insert(*keysym, level);
+                    }
+                }
+            }
+        }
+        self.mapping = mapping;
+    }
+}
+
+/// Checks if X-Resource extension supports querying client pids.
+fn res_enabled(conn: &impl Connection) -> bool {
+    res::query_version(conn, 1, 2).ok().
+        and_then(|c| c.reply().ok()).
+        is_some_and(|r| (r.server_major, r.server_minor) >= (1, 2))
+}
+
+/// Returns pid of process owning `win`, `_NET_WM_PID` is translated using pid of the X client
+/// reported by X-Resource extension, which also covers windows without `_NET_WM_PID`.
+fn window_pid(conn: &impl Connection, win: u32, wm_pid_atom: u32, res_enabled: bool) -> Option<u32> {
+    let wm_pid = get_property(conn, false, win, wm_pid_atom, AtomEnum::CARDINAL, 0, 1).ok().
+        and_then(|v| v.reply().ok().
+            and_then(|r| r.value32().
+                and_then(|mut v| v.next())));
+    let client_pid = if res_enabled {
+        let spec = ClientIdSpec { client: win, mask: ClientIdMask::LocalClientPID.into() };
+        query_client_ids(conn, &[spec]).ok().
+            and_then(|c| c.reply().ok()).
+            and_then(|r| r.ids.first()?.value.first().copied())
+    } else {
+        None
+    };
+    match (wm_pid, client_pid) {
+        (Some(pid), Some(client_pid)) => Some(translate_pid(pid, client_pid)),
+        (pid, client_pid) => pid.or(client_pid),
+    }
+}
+
+/// Returns class part of `WM_CLASS` value (`instance\0class\0`), instance when class is missing.
+fn parse_wm_class(value: &[u8]) -> String {
+    let mut parts = value.split(|c| *c == 0).filter(|part| !part.is_empty());
+    let instance = parts.next();
+    parts.next().or(instance).map_or_else(String::new, |class| String::from_utf8_lossy(class).into_owned())
+}
+
+/// Returns class and title of `win`.
+fn window_class_and_title(conn: &impl Connection, win: u32, atoms: &AtomCollection) -> (String, String) {
+    let property = |property: u32, property_type: u32|
+        get_property(conn, false, win, property, property_type, 0, 1024).ok().
+            and_then(|c| c.reply().ok()).
+            map(|r| r.value);
+    let class = property(AtomEnum::WM_CLASS.into(), AtomEnum::STRING.into()).
+        map_or_else(String::new, |v| parse_wm_class(&v));
+    let title = property(atoms._NET_WM_NAME, atoms.UTF8_STRING).
+        map_or_else(String::new, |v| String::from_utf8_lossy(&v).into_owned());
+    (class, title)
+}
+
+/// Finds processes owning X11 windows for focus sources which know only window id,
+/// like i3 which doesn't report pid in its IPC.
+#[cfg(feature = "sway")]
+pub(crate) struct WindowPids {
+    conn: RustConnection,
+    wm_pid_atom: u32,
+    res_enabled: bool,
+}
+
+#[cfg(feature = "sway")]
+impl WindowPids {
+    pub(crate) fn connect() -> Option<WindowPids> {
+        let (conn, _) = RustConnection::connect(None).ok()?;
+        let wm_pid_atom = AtomCollection::new(&conn).ok()?.reply().ok()?._NET_WM_PID;
+        let res_enabled = res_enabled(&conn);
+        Some(WindowPids {
+            conn,
+            wm_pid_atom,
+            res_enabled,
+        })
+    }
+
+    pub(crate) fn pid(&self, win: u32) -> Option<u32> {
+        window_pid(&self.conn, win, self.wm_pid_atom, self.res_enabled)
+    }
+}
+
+/// Returns fake input events typing `keycode` with `modifiers` while modifier keys in `held`
+/// are pressed. Held modifiers that aren't needed are released and pressed again afterwards,
+/// missing ones are pressed and released afterwards.
+fn keypress_events(keycode: u8, modifiers: u8, held: &[(u8, u8)], keycodes_of_mods: &[(u8, u8)]) -> Vec<(u8, u8)> {
+    let mut events = Vec::new();
+    let mut to_restore = Vec::new();
+    let mut held_modifiers = 0u8;
+    for (mod_keycode, modifier) in held {
+        if *modifier & modifiers == 0 {
+            events.push((KEY_RELEASE_EVENT, *mod_keycode));
+            to_restore.push((KEY_PRESS_EVENT, *mod_keycode));
+        } else {
+            held_modifiers |= *modifier;
+        }
+    }
+    let mut modifiers_to_press = modifiers & !held_modifiers;
+    for (mod_keycode, modifier) in keycodes_of_mods {
+        if *modifier & modifiers_to_press != 0 {
+            events.push((KEY_PRESS_EVENT, *mod_keycode));
+            to_restore.push((KEY_RELEASE_EVENT, *mod_keycode));
+            modifiers_to_press &= !*modifier;
+        }
+    }
+    events.push((KEY_PRESS_EVENT, keycode));
+    events.push((KEY_RELEASE_EVENT, keycode));
+    events.extend(to_restore);
+    events
+}
+
+fn send_keypress(conn: &impl Connection, keycode: u8, modifiers: u8, keycodes_of_mods: &[(u8, u8)]) {
+    let held: Vec<(u8, u8)> = query_keymap(conn).ok().and_then(|c| c.reply().ok()).
+        map_or_else(Vec::new, |r| keycodes_of_mods.iter().
+            filter(|(mod_keycode, _)| r.keys[(*mod_keycode / 8) as usize] & (1 << (*mod_keycode & 7)) != 0).
+            copied().collect());
+    for (event, event_keycode) in keypress_events(keycode, modifiers, &held, keycodes_of_mods) {
+        let _ = fake_input(conn, event, event_keycode, CURRENT_TIME, NONE, 0, 0, 0);
+        let _ = conn.flush();
+    }
+}
+
+fn send_scroll(conn: &impl Connection, amount: i32) {
+    let button = if amount < 0 { 4 } else { 5 };
+    for _ in 0..amount.abs() {
+        let _ = fake_input(conn, BUTTON_PRESS_EVENT, button, CURRENT_TIME, NONE, 0, 0, 0);
+        let _ = fake_input(conn, BUTTON_RELEASE_EVENT, button, CURRENT_TIME, NONE, 0, 0, 0);
     }
     let _ = conn.flush();
 }
 
-fn x11_listener(sender: Sender<StateChanges>, receiver: Receiver<X11Commands>, mut poll: Poll,
-                debug_enabled: bool) -> ()
-{
+/// Sends keys and reports focus changes until connection to X server is lost.
+fn x11_listener(conn: RustConnection, screen_num: usize, sender: &Sender<StateChanges>, receiver: &Receiver<X11Commands>,
+                poll: &mut Poll, track_focus: bool) -> io::Result<()> {
     let mut events = Events::with_capacity(2);
 
-    let (conn, screen_num) = RustConnection::connect(None).unwrap();
-    let (mapping, keycodes_of_mods) = keysym_to_keycode_mapping(&conn);
+    let xkb_enabled = enable_xkb(&conn);
+    let res_enabled = res_enabled(&conn);
+    let mut keymap = Keymap::new(&conn, xkb_enabled);
     let screen = &conn.setup().roots[screen_num];
     let root_win = screen.root;
-    let atoms = AtomCollection::new(&conn).unwrap().reply().unwrap();
+    let atoms = AtomCollection::new(&conn).map_err(io::Error::other)?.reply().map_err(io::Error::other)?;
 
-    if change_window_attributes(&conn, root_win, &ChangeWindowAttributesAux::new().
+    if track_focus && change_window_attributes(&conn, root_win, &ChangeWindowAttributesAux::new().
         event_mask(EventMask::PropertyChange)).is_ok()
     {
         let _ = conn.flush();
@@ -142,65 +434,78 @@ fn x11_listener(sender: Sender<StateChanges>, receiver: Receiver<X11Commands>, m
 
     let x11_token = Token(0);
 
-    poll.registry().register(&mut SourceFd(&conn.stream().as_raw_fd()), x11_token, Interest::READABLE).unwrap();
+    let fd = conn.stream().as_raw_fd();
+    poll.registry().register(&mut SourceFd(&fd), x11_token, Interest::READABLE)?;
 
     loop {
-        let _ = poll.poll(&mut events, None);
+        if let Err(err) = poll.poll(&mut events, None) {
+            if err.kind() != ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
         for event in &events {
             if event.token() != x11_token {
-                if let Ok(command) = receiver.try_recv() {
+                while let Ok(command) = receiver.try_recv() {
                     match command {
                         X11Commands::SendKey { keysym, modifiers: key_modifiers } => {
-                            if let Some((keycode, modifiers)) = mapping.get(&keysym) {
-                                if debug_enabled {
-                                    println!("command {:x?} {:x?} {:x?}, {:x?}", keycode, keysym, modifiers, key_modifiers);
-                                }
-                                send_keypress(&conn, *keycode, key_modifiers, &keycodes_of_mods);
+                            if let Some((keycode, level_modifiers)) = keymap.mapping.get(&keysym) {
+                                debug!("command {:x?} {:x?} {:x?}, {:x?}", keycode, keysym, level_modifiers, key_modifiers);
+                                send_keypress(&conn, *keycode, key_modifiers | level_modifiers, &keymap.keycodes_of_mods);
+                            } else {
+                                warn!("Keysym {:x?} isn't reachable with any key in group {}", keysym, keymap.group);
                             }
                         }
+                        X11Commands::Scroll { amount } => {
+                            send_scroll(&conn, amount);
+                        }
                     }
                 }
             } else {
                 loop {
-                    if let Ok(Some(event)) = conn.poll_for_event() {
-                        match event {
-                            Event::PropertyNotify(prop_notify) => {
-                                if prop_notify.atom == atoms._NET_ACTIVE_WINDOW {
-                                    let root_win = prop_notify.window;
-                                    if let Some(win) =
-                                    get_property(&conn, false, root_win, atoms._NET_ACTIVE_WINDOW,
-                                                 AtomEnum::WINDOW, 0, 1).
-                                        map_or(None, |p| p.reply().
-                                            map_or(None, |r| r.value32().
-                                                map_or(None, |mut v| v.next())))
-                                    {
-                                        if let Some(pid) =
-                                        get_property(&conn, false, win, atoms._NET_WM_PID,
-                                                     AtomEnum::CARDINAL, 0, 1).
-                                            map_or(None, |v| v.reply().
-                                                map_or(None, |r| r.value32().
-                                                    map_or(None, |mut v| v.next())))
-                                        {
-                                            let program =
-                                                if let Ok(path) = read_link(format!("/proc/{:}/exe", pid)) {
-                                                    path.to_string_lossy().to_string()
-                                                } else {
-                                                    "".to_owned()
-                                                };
-                                            if debug_enabled {
-                                                println!("App switch: {} {}", pid, program);
-                                            }
-                                            let _ = sender.send(StateChanges::FocusChanged { pid, program });
-                                        } else {
-                                            let _ = sender.send(StateChanges::FocusChanged { pid: 0, program: "".to_owned() });
-                                        }
-                                    }
-                                }
+                    let event = match conn.poll_for_event() {
+                        Ok(Some(event)) => event,
+                        Ok(None) => break,
+                        Err(err) => {
+                            let _ = poll.registry().deregister(&mut SourceFd(&fd));
+                            return Err(io::Error::other(err));
+                        }
+                    };
+                    match event {
+                        Event::PropertyNotify(prop_notify) if prop_notify.atom == atoms._NET_ACTIVE_WINDOW => {
+                            let root_win = prop_notify.window;
+                            if let Some(win) =
+                            get_property(&conn, false, root_win, atoms._NET_ACTIVE_WINDOW,
+                                         AtomEnum::WINDOW, 0, 1).ok().
+                                and_then(|p| p.reply().ok().
+                                    and_then(|r| r.value32().
+                                        and_then(|mut v| v.next())))
+                            {
+                                let pid = window_pid(&conn, win, atoms._NET_WM_PID, res_enabled).unwrap_or(0);
+                                let program = if pid != 0 { program_path(pid) } else { "".to_owned() };
+                                let (class, title) = window_class_and_title(&conn, win, &atoms);
+                                debug!("App switch: {} {} {}", pid, program, class);
+                                let _ = sender.send(StateChanges::FocusChanged {
+                                    pid,
+                                    program,
+                                    sandbox_id: sandbox_id(pid),
+                                    cmdline: command_line(pid),
+                                    class,
+                                    title,
+                                });
                             }
-                            _ => {}
                         }
-                    } else {
-                        break;
+                        Event::MappingNotify(_) if !xkb_enabled => {
+                            keymap.reload(&conn);
+                        }
+                        Event::XkbMapNotify(_) | Event::XkbNewKeyboardNotify(_) => {
+                            keymap.reload(&conn);
+                            debug!("Keyboard mapping changed, group {}", keymap.group);
+                        }
+                        Event::XkbStateNotify(state) if state.changed & u16::from(StatePart::GroupState) != 0 => {
+                            keymap.set_group(state.group.into());
+                            debug!("Keyboard group changed to {}", keymap.group);
+                        }
+                        _ => {}
                     }
                 }
             }
@@ -208,3 +513,149 @@ fn x11_listener(sender: Sender<StateChanges>, receiver: Receiver<X11Commands>, m
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x11rb::protocol::xkb::KTMapEntry;
+
+    #[test]
+    fn wm_class() {
+        assert_eq!(parse_wm_class(b"Navigator\0firefox\0"), "firefox");
+        assert_eq!(parse_wm_class(b"xterm\0"), "xterm");
+        assert_eq!(parse_wm_class(b""), "");
+    }
+
+    fn key(groups: u8, width: u8, syms: &[u32]) -> KeySymMap {
+        KeySymMap {
+            kt_index: [0; 4],
+            group_info: groups,
+            width,
+            syms: syms.to_vec(),
+        }
+    }
+
+    /// Key type with Shift selecting level 2.
+    fn two_level() -> KeyType {
+        KeyType {
+            mods_mask: SHIFT_MASK,
+            mods_mods: SHIFT_MASK,
+            mods_vmods: 0,
+            num_levels: 2,
+            has_preserve: false,
+            map: vec![KTMapEntry { active: true, mods_mask: SHIFT_MASK, level: 1, mods_mods: SHIFT_MASK, mods_vmods: 0 }],
+            preserve: Vec::new(),
+        }
+    }
+
+    fn keymap(syms: Vec<KeySymMap>) -> Keymap {
+        Keymap {
+            xkb_enabled: true,
+            first_keycode: 8,
+            types: vec![two_level()],
+            syms,
+            group: 0,
+            mapping: HashMap::new(),
+            keycodes_of_mods: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn out_of_range_groups() {
+        assert_eq!(effective_group(0x00, 0), None);
+        assert_eq!(effective_group(0x02, 1), Some(1));
+        // Wrap
+        assert_eq!(effective_group(0x02, 3), Some(1));
+        assert_eq!(effective_group(0x01, 2), Some(0));
+        // Clamp
+        assert_eq!(effective_group(0x42, 3), Some(1));
+        // Redirect to group in bits 4-5, first group when it's out of range too
+        assert_eq!(effective_group(0x93, 3), Some(1));
+        assert_eq!(effective_group(0xb2, 2), Some(0));
+    }
+
+    #[test]
+    fn mapping_follows_active_group() {
+        let mut keymap = keymap(vec![
+            // a A / ф Ф
+            key(0x02, 2, &[0x61, 0x41, 0x6c6, 0x6e6]),
+            // Only one group, used in all groups
+            key(0x01, 1, &[0xff0d]),
+            key(0x00, 0, &[]),
+            // Second key producing `a` isn't used
+            key(0x01, 2, &[0x61, 0x41]),
+        ]);
+        keymap.rebuild();
+        assert_eq!(keymap.mapping.get(&0x61), Some(&(8, 0)));
+        assert_eq!(keymap.mapping.get(&0x41), Some(&(8, 1)));
+        assert_eq!(keymap.mapping.get(&0xff0d), Some(&(9, 0)));
+        assert_eq!(keymap.mapping.get(&0x6c6), None);
+
+        keymap.set_group(1);
+        assert_eq!(keymap.mapping.get(&0x6c6), Some(&(8, 0)));
+        assert_eq!(keymap.mapping.get(&0x6e6), Some(&(8, 1)));
+        assert_eq!(keymap.mapping.get(&0xff0d), Some(&(9, 0)));
+        assert_eq!(keymap.mapping.get(&0x61), Some(&(11, 0)));
+    }
+
+    #[test]
+    fn core_mapping_levels() {
+        // Keycodes 10-13 with 6 columns: `a`, `e` with `€` at level 3, ISO_Level3_Shift and Mode_switch
+        let keysyms = [
+            0x61, 0x41, 0, 0, 0, 0,
+            0x65, 0x45, 0, 0, 0x20ac, 0,
+            ISO_LEVEL3_SHIFT, 0, 0, 0, 0, 0,
+            MODE_SWITCH, 0, 0x6c6, 0, 0, 0,
+        ];
+        let keycodes_of_mods = [(12, 0x80), (13, 0x08)];
+        let mapping = core_mapping(&keysyms, 6, 10, &keycodes_of_mods);
+        assert_eq!(mapping.get(&0x61), Some(&(10, 0)));
+        assert_eq!(mapping.get(&0x45), Some(&(11, SHIFT_MASK)));
+        assert_eq!(mapping.get(&0x20ac), Some(&(11, 0x80)));
+        assert_eq!(mapping.get(&0x6c6), Some(&(13, 0x08)));
+
+        // Level 3 isn't reachable without ISO_Level3_Shift on modifier key
+        let mapping = core_mapping(&keysyms, 6, 10, &[]);
+        assert_eq!(mapping.get(&0x20ac), None);
+        assert_eq!(mapping.get(&0x65), Some(&(11, 0)));
+    }
+    #[test]
+    fn level_modifiers_skip_lock_and_inactive_entries() {
+        let mut key_type = two_level();
+        key_type.num_levels = 3;
+        key_type.map = vec![
+            KTMapEntry { active: true, mods_mask: LOCK_MASK, level: 1, mods_mods: LOCK_MASK, mods_vmods: 0 },
+            KTMapEntry { active: true, mods_mask: SHIFT_MASK, level: 1, mods_mods: SHIFT_MASK, mods_vmods: 0 },
+            KTMapEntry { active: false, mods_mask: 0x80, level: 2, mods_mods: 0x80, mods_vmods: 0 },
+            KTMapEntry { active: true, mods_mask: 0x80 | SHIFT_MASK, level: 2, mods_mods: 0x80, mods_vmods: 0 },
+        ];
+        let mut keymap = keymap(Vec::new());
+        keymap.types = vec![key_type];
+        assert_eq!(keymap.level_modifiers(0, 0), Some(0));
+        assert_eq!(keymap.level_modifiers(0, 1), Some(SHIFT_MASK));
+        assert_eq!(keymap.level_modifiers(0, 2), Some(0x80 | SHIFT_MASK));
+        assert_eq!(keymap.level_modifiers(0, 3), None);
+        assert_eq!(keymap.level_modifiers(1, 1), None);
+    }
+
+    #[test]
+    fn held_modifiers_are_kept_when_needed() {
+        // Shift on 50 and 62, Control on 37, Mod5 on 92
+        let keycodes_of_mods = [(50, SHIFT_MASK), (62, SHIFT_MASK), (37, 0x04), (92, 0x80)];
+        // Held Shift is needed, held Control is released and pressed again
+        assert_eq!(keypress_events(38, SHIFT_MASK, &[(62, SHIFT_MASK), (37, 0x04)], &keycodes_of_mods), vec![
+            (KEY_RELEASE_EVENT, 37),
+            (KEY_PRESS_EVENT, 38),
+            (KEY_RELEASE_EVENT, 38),
+            (KEY_PRESS_EVENT, 37),
+        ]);
+        // Missing modifiers are pressed with the first key mapped to them
+        assert_eq!(keypress_events(26, SHIFT_MASK | 0x80, &[], &keycodes_of_mods), vec![
+            (KEY_PRESS_EVENT, 50),
+            (KEY_PRESS_EVENT, 92),
+            (KEY_PRESS_EVENT, 26),
+            (KEY_RELEASE_EVENT, 26),
+            (KEY_RELEASE_EVENT, 50),
+            (KEY_RELEASE_EVENT, 92),
+        ]);
+    }
+}